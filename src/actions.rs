@@ -1,7 +1,23 @@
 use crate::{
+    bitbang::{check_shift_out, run_shift_out, BitOrder, DEFAULT_CLOCK_US},
     config::save_actions,
-    gpio::{PinLevel, PullType},
-    logger::{log_error, log_info},
+    debounce::wait_for_level,
+    errors::{ActionError, GpioError},
+    gpio::{Gpio, PinDirection, PinLevel, PullType},
+    hex::{parse_bytes, parse_u8, to_hex},
+    hwpwm::pwm_pin,
+    i2c::{
+        read_register, write_register, LinuxI2c, DEFAULT_I2C_BUS,
+        FIRST_ADDRESS, LAST_ADDRESS,
+    },
+    logger::{escape_html, log_error, log_info},
+    names::resolve,
+    pwm::MAX_SOFT_PWM_FREQ,
+    sensors::{read_sensor, wait_for_sensor, Comparison, Quantity, Sensor},
+    servo::{cancel_moves, move_servo},
+    spi::{SpiConfig, Spidev, DEFAULT_SPI_BUS},
+    timing::{precise_sleep, RunStats, PRECISE_DELAY_MAX_US},
+    uart::unescape,
     AppState,
 };
 
@@ -13,8 +29,8 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use std::{
     fmt::{self, Display},
-    sync::atomic::Ordering,
-    time::Duration,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 use tokio::time::sleep;
 
@@ -23,16 +39,62 @@ pub enum Action {
     SetHigh(i32),
     SetLow(i32),
     Delay(i32),
+    DelayUs(i32),
     WaitForHigh(i32),
     WaitForLow(i32),
     SetPullUp(i32),
     SetPullDown(i32),
+    // pin, width in us, active level
+    Pulse(i32, i32, PinLevel),
+    Toggle(i32),
+    // pin, frequency in hz, duty in %
+    // a frequency of 0 stops pwm on the pin
+    SetPwm(i32, u32, f32),
+    // same as SetPwm but on the pwm peripheral,
+    // only GPIO 12, 13, 18 and 19
+    SetHwPwm(i32, u32, f32),
+    // pin, degrees
+    ServoAngle(i32, f32),
+    // pin, degrees, over how many ms
+    ServoMove(i32, f32, u32),
+    // address, register, bytes on the default bus
+    I2cWrite(u8, u8, Vec<u8>),
+    // address, register, how many bytes to log
+    I2cRead(u8, u8, u8),
+    // chip select on SPI0, bytes to clock out
+    SpiTransfer(u8, Vec<u8>),
+    // text for the open uart, escapes already applied
+    UartSend(String),
+    // pattern, timeout in ms
+    UartExpect(String, u32),
+    // data, clock, latch, bytes, bit order
+    ShiftOut(i32, i32, i32, Vec<u8>, BitOrder),
+    // logs one reading
+    ReadSensor(Sensor),
+    // blocks until the reading is above/below the value
+    WaitForSensor(Sensor, Quantity, Comparison, f32),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ActionForm {
     pub action_type: String,
-    pub value: i32,
+    // a number, or a pin name for the pin actions
+    pub value: String,
+    // comma separated extras for actions
+    // that need more than one value
+    pub args: Option<String>,
+}
+
+impl ActionForm {
+    fn args(&self) -> Vec<&str> {
+        self.args
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|arg| !arg.is_empty())
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,6 +102,440 @@ pub struct LoopOption {
     pub should_loop: Option<String>,
 }
 
+impl Action {
+    pub fn from_form(form: &ActionForm) -> Result<Self, ActionError> {
+        // only pin actions take a name, a delay of "pump"
+        // shouldn't quietly become pump's gpio number
+        let pin = || resolve(&form.value).map_err(ActionError::InvalidArgument);
+        let number = || {
+            form.value.trim().parse::<i32>().map_err(|_| {
+                ActionError::InvalidArgument(format!(
+                    "expected a number, got `{}`",
+                    escape_html(form.value.trim())
+                ))
+            })
+        };
+        let action = match form.action_type.as_str() {
+            "set-high" => Action::SetHigh(pin()?),
+            "set-low" => Action::SetLow(pin()?),
+            "delay" => Action::Delay(number()?),
+            "delay-us" => Action::DelayUs(number()?),
+            "wait-for-high" => Action::WaitForHigh(pin()?),
+            "wait-for-low" => Action::WaitForLow(pin()?),
+            "set-pull-up" => Action::SetPullUp(pin()?),
+            "set-pull-down" => Action::SetPullDown(pin()?),
+            "toggle" => Action::Toggle(pin()?),
+            "pulse" => {
+                let args = form.args();
+                let width = args
+                    .first()
+                    .ok_or_else(|| {
+                        ActionError::InvalidArgument(
+                            "pulse needs a width in us".to_string(),
+                        )
+                    })?
+                    .parse::<i32>()
+                    .map_err(|e| {
+                        ActionError::InvalidArgument(format!("width: {e}"))
+                    })?;
+                let level = match args.get(1) {
+                    Some(level) => level
+                        .parse::<PinLevel>()
+                        .map_err(ActionError::InvalidArgument)?,
+                    None => PinLevel::High,
+                };
+                Action::Pulse(pin()?, width, level)
+            }
+            "set-pwm" | "set-hw-pwm" => {
+                let args = form.args();
+                let (Some(freq), Some(duty)) = (args.first(), args.get(1))
+                else {
+                    return Err(ActionError::InvalidArgument(
+                        "pwm needs a frequency and duty".to_string(),
+                    ));
+                };
+                let freq = freq.parse::<u32>().map_err(|e| {
+                    ActionError::InvalidArgument(format!("frequency: {e}"))
+                })?;
+                let duty = duty.parse::<f32>().map_err(|e| {
+                    ActionError::InvalidArgument(format!("duty: {e}"))
+                })?;
+                match form.action_type.as_str() {
+                    "set-hw-pwm" => Action::SetHwPwm(pin()?, freq, duty),
+                    _ => Action::SetPwm(pin()?, freq, duty),
+                }
+            }
+            "servo-angle" | "servo-move" => {
+                let args = form.args();
+                let angle = args
+                    .first()
+                    .ok_or_else(|| {
+                        ActionError::InvalidArgument(
+                            "servo needs an angle".to_string(),
+                        )
+                    })?
+                    .parse::<f32>()
+                    .map_err(|e| {
+                        ActionError::InvalidArgument(format!("angle: {e}"))
+                    })?;
+
+                if form.action_type == "servo-angle" {
+                    Action::ServoAngle(pin()?, angle)
+                } else {
+                    let time = args
+                        .get(1)
+                        .ok_or_else(|| {
+                            ActionError::InvalidArgument(
+                                "servo move needs a duration in ms".to_string(),
+                            )
+                        })?
+                        .parse::<u32>()
+                        .map_err(|e| {
+                            ActionError::InvalidArgument(format!("time: {e}"))
+                        })?;
+                    Action::ServoMove(pin()?, angle, time)
+                }
+            }
+            "i2c-write" | "i2c-read" => {
+                let value = number()?;
+                let addr = u8::try_from(value).map_err(|_| {
+                    ActionError::InvalidArgument(format!(
+                        "i2c address: {value}"
+                    ))
+                })?;
+                let args = form.args();
+                let reg = args
+                    .first()
+                    .ok_or_else(|| {
+                        ActionError::InvalidArgument(
+                            "i2c needs a register".to_string(),
+                        )
+                    })
+                    .and_then(|reg| {
+                        parse_u8(reg).map_err(ActionError::InvalidArgument)
+                    })?;
+
+                if form.action_type == "i2c-write" {
+                    let bytes = parse_bytes(&args[1..].join(" "))
+                        .map_err(ActionError::InvalidArgument)?;
+                    Action::I2cWrite(addr, reg, bytes)
+                } else {
+                    let len = match args.get(1) {
+                        Some(len) => parse_u8(len)
+                            .map_err(ActionError::InvalidArgument)?,
+                        None => 1,
+                    };
+                    Action::I2cRead(addr, reg, len)
+                }
+            }
+            "spi-transfer" => {
+                let value = number()?;
+                let cs = u8::try_from(value).map_err(|_| {
+                    ActionError::InvalidArgument(format!(
+                        "spi chip select: {value}"
+                    ))
+                })?;
+                let bytes = parse_bytes(&form.args().join(" "))
+                    .map_err(ActionError::InvalidArgument)?;
+                Action::SpiTransfer(cs, bytes)
+            }
+            "shift-out" => {
+                let args = form.args();
+                let (Some(clock), Some(latch), Some(bytes)) =
+                    (args.first(), args.get(1), args.get(2))
+                else {
+                    return Err(ActionError::InvalidArgument(
+                        "shift out needs clock, latch and bytes".to_string(),
+                    ));
+                };
+                let clock = resolve(clock).map_err(|e| {
+                    ActionError::InvalidArgument(format!("clock pin: {e}"))
+                })?;
+                let latch = resolve(latch).map_err(|e| {
+                    ActionError::InvalidArgument(format!("latch pin: {e}"))
+                })?;
+                let bytes =
+                    parse_bytes(bytes).map_err(ActionError::InvalidArgument)?;
+                let order = match args.get(3) {
+                    Some(order) => order
+                        .parse::<BitOrder>()
+                        .map_err(ActionError::InvalidArgument)?,
+                    None => BitOrder::MsbFirst,
+                };
+                Action::ShiftOut(pin()?, clock, latch, bytes, order)
+            }
+            "read-sensor" => {
+                let sensor = form
+                    .args
+                    .as_deref()
+                    .unwrap_or("")
+                    .parse::<Sensor>()
+                    .map_err(ActionError::InvalidArgument)?;
+                Action::ReadSensor(sensor)
+            }
+            "wait-for-sensor" => {
+                let args = form.args();
+                let [sensor, quantity, comparison, threshold] = args[..] else {
+                    return Err(ActionError::InvalidArgument(
+                        "wait for sensor needs sensor, quantity, \
+                         above/below and a value"
+                            .to_string(),
+                    ));
+                };
+                let threshold = threshold.parse::<f32>().map_err(|e| {
+                    ActionError::InvalidArgument(format!("value: {e}"))
+                })?;
+                Action::WaitForSensor(
+                    sensor.parse().map_err(ActionError::InvalidArgument)?,
+                    quantity.parse().map_err(ActionError::InvalidArgument)?,
+                    comparison.parse().map_err(ActionError::InvalidArgument)?,
+                    threshold,
+                )
+            }
+            // the whole args box is the text,
+            // commas and all
+            "uart-send" => {
+                Action::UartSend(unescape(form.args.as_deref().unwrap_or("")))
+            }
+            "uart-expect" => {
+                let value = number()?;
+                let time = u32::try_from(value)
+                    .map_err(|_| ActionError::NegativeDelay(value))?;
+                let pattern = unescape(form.args.as_deref().unwrap_or(""));
+                Action::UartExpect(pattern, time)
+            }
+            other => {
+                return Err(ActionError::UnknownAction(other.to_string()));
+            }
+        };
+
+        Ok(action)
+    }
+
+    // catch bad values when adding rather
+    // than halfway through a run
+    pub fn validate(&self, gpio: &Gpio) -> Result<(), ActionError> {
+        match self {
+            Action::Delay(time) | Action::DelayUs(time) => {
+                if *time < 0 {
+                    return Err(ActionError::NegativeDelay(*time));
+                }
+                Ok(())
+            }
+            Action::SetHigh(pin)
+            | Action::SetLow(pin)
+            | Action::WaitForHigh(pin)
+            | Action::WaitForLow(pin)
+            | Action::SetPullUp(pin)
+            | Action::SetPullDown(pin)
+            | Action::Toggle(pin) => validate_pin(*pin, gpio),
+            Action::Pulse(pin, width, _) => {
+                if *width < 0 {
+                    return Err(ActionError::NegativeDelay(*width));
+                }
+                // holds the rt thread and the gpio
+                // lock for the whole pulse
+                if *width as u64 > PRECISE_DELAY_MAX_US {
+                    return Err(ActionError::InvalidArgument(format!(
+                        "pulse width must be at most {PRECISE_DELAY_MAX_US}us"
+                    )));
+                }
+                validate_pin(*pin, gpio)
+            }
+            Action::SetPwm(pin, freq, duty) => {
+                if *freq > MAX_SOFT_PWM_FREQ {
+                    return Err(ActionError::InvalidArgument(format!(
+                        "pwm frequency must be at most {MAX_SOFT_PWM_FREQ}Hz"
+                    )));
+                }
+                if !(0.0..=100.0).contains(duty) {
+                    return Err(ActionError::InvalidArgument(
+                        "pwm duty must be between 0 and 100%".to_string(),
+                    ));
+                }
+                validate_pin(*pin, gpio)
+            }
+            Action::SetHwPwm(pin, _, duty) => {
+                if pwm_pin(*pin).is_none() {
+                    return Err(ActionError::InvalidArgument(format!(
+                        "GPIO {pin} has no hardware pwm, use 12, 13, 18 or 19"
+                    )));
+                }
+                if !(0.0..=100.0).contains(duty) {
+                    return Err(ActionError::InvalidArgument(
+                        "pwm duty must be between 0 and 100%".to_string(),
+                    ));
+                }
+                validate_pin(*pin, gpio)
+            }
+            // limits live on the servo config and
+            // get checked when the move happens
+            Action::ServoAngle(pin, angle)
+            | Action::ServoMove(pin, angle, _) => {
+                if !angle.is_finite() {
+                    return Err(ActionError::InvalidArgument(format!(
+                        "angle: {angle}"
+                    )));
+                }
+                validate_pin(*pin, gpio)
+            }
+            Action::I2cWrite(addr, _, _) | Action::I2cRead(addr, _, _) => {
+                if !(FIRST_ADDRESS..=LAST_ADDRESS).contains(addr) {
+                    return Err(ActionError::InvalidArgument(format!(
+                        "i2c address must be {FIRST_ADDRESS:#04x}-{LAST_ADDRESS:#04x}"
+                    )));
+                }
+                Ok(())
+            }
+            Action::SpiTransfer(_, bytes) => {
+                if bytes.is_empty() {
+                    return Err(ActionError::InvalidArgument(
+                        "spi needs at least one byte".to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            Action::UartSend(text) | Action::UartExpect(text, _) => {
+                if text.is_empty() {
+                    return Err(ActionError::InvalidArgument(
+                        "uart needs some text".to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            Action::ShiftOut(data, clock, latch, bytes, _) => {
+                check_shift_out(
+                    [*data, *clock, *latch],
+                    bytes.len(),
+                    DEFAULT_CLOCK_US,
+                )
+                .map_err(ActionError::InvalidArgument)?;
+                validate_pin(*data, gpio)?;
+                validate_pin(*clock, gpio)?;
+                validate_pin(*latch, gpio)
+            }
+            Action::ReadSensor(sensor) => match sensor.pin() {
+                Some(pin) => validate_pin(pin, gpio),
+                None => Ok(()),
+            },
+            Action::WaitForSensor(sensor, quantity, _, threshold) => {
+                if !threshold.is_finite() {
+                    return Err(ActionError::InvalidArgument(format!(
+                        "value: {threshold}"
+                    )));
+                }
+                if let (Sensor::Ds18b20(_), Quantity::Humidity) =
+                    (sensor, quantity)
+                {
+                    return Err(ActionError::InvalidArgument(
+                        "DS18B20 doesn't measure humidity".to_string(),
+                    ));
+                }
+                match sensor.pin() {
+                    Some(pin) => validate_pin(pin, gpio),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    pub fn display_text(&self) -> String {
+        match self {
+            Action::SetHigh(pin) => format!("GPIO:{} Set High", pin),
+            Action::SetLow(pin) => format!("GPIO:{} Set Low", pin),
+            Action::Delay(time) => format!("Delay {}ms", time),
+            Action::DelayUs(time) => format!("Delay {}us", time),
+            Action::WaitForHigh(pin) => format!("Wait For HIGH GPIO:{}", pin),
+            Action::WaitForLow(pin) => format!("Wait For LOW GPIO:{}", pin),
+            Action::SetPullUp(pin) => format!("GPIO:{} Pull-Up", pin),
+            Action::SetPullDown(pin) => format!("GPIO:{} Pull-Down", pin),
+            Action::Pulse(pin, width, level) => {
+                format!("GPIO:{} Pulse {} {}us", pin, level, width)
+            }
+            Action::Toggle(pin) => format!("GPIO:{} Toggle", pin),
+            Action::SetPwm(pin, 0, _) => format!("GPIO:{} PWM Off", pin),
+            Action::SetPwm(pin, freq, duty) => {
+                format!("GPIO:{} PWM {}Hz {}%", pin, freq, duty)
+            }
+            Action::SetHwPwm(pin, 0, _) => format!("GPIO:{} HW PWM Off", pin),
+            Action::SetHwPwm(pin, freq, duty) => {
+                format!("GPIO:{} HW PWM {}Hz {}%", pin, freq, duty)
+            }
+            Action::ServoAngle(pin, angle) => {
+                format!("GPIO:{} Servo {} deg", pin, angle)
+            }
+            Action::ServoMove(pin, angle, time) => {
+                format!("GPIO:{} Servo {} deg over {}ms", pin, angle, time)
+            }
+            Action::I2cWrite(addr, reg, bytes) => {
+                format!(
+                    "I2C:{:#04x} Write {:#04x} {}",
+                    addr,
+                    reg,
+                    to_hex(bytes)
+                )
+            }
+            Action::I2cRead(addr, reg, len) => {
+                format!("I2C:{:#04x} Read {:#04x} x{}", addr, reg, len)
+            }
+            Action::SpiTransfer(cs, bytes) => {
+                format!(
+                    "SPI:{}.{} Transfer {}",
+                    DEFAULT_SPI_BUS,
+                    cs,
+                    to_hex(bytes)
+                )
+            }
+            Action::UartSend(text) => {
+                format!("UART Send {}", escape_html(&format!("{:?}", text)))
+            }
+            Action::UartExpect(pattern, time) => {
+                format!(
+                    "UART Expect {} within {}ms",
+                    escape_html(&format!("{:?}", pattern)),
+                    time
+                )
+            }
+            Action::ReadSensor(sensor) => format!("Read {}", sensor),
+            Action::WaitForSensor(sensor, quantity, comparison, threshold) => {
+                format!(
+                    "Wait For {} {} {} {}",
+                    sensor, quantity, comparison, threshold
+                )
+            }
+            Action::ShiftOut(data, clock, latch, bytes, order) => {
+                format!(
+                    "GPIO:{}/{}/{} Shift Out {} {}",
+                    data,
+                    clock,
+                    latch,
+                    order,
+                    to_hex(bytes)
+                )
+            }
+        }
+    }
+}
+
+// ID_SD/ID_SC, the firmware reads the HAT EEPROM over
+// them at boot. the power and ground pins have no gpio
+// number so the range check already keeps them out
+const RESERVED_PINS: [i32; 2] = [0, 1];
+
+// gpio pins should be between 0-27
+// and actually be on the header
+fn validate_pin(pin: i32, gpio: &Gpio) -> Result<(), ActionError> {
+    if !(0..=27).contains(&pin) || gpio.pin_type(pin).is_none() {
+        return Err(ActionError::InvalidPin(pin));
+    }
+
+    if RESERVED_PINS.contains(&pin) {
+        return Err(ActionError::ReservedPin(pin));
+    }
+
+    Ok(())
+}
+
 impl Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -48,6 +544,7 @@ impl Display for Action {
             }
             Action::SetLow(pin) => write!(f, "SETLOW{pin}"),
             Action::Delay(time) => write!(f, "DELAY{time}"),
+            Action::DelayUs(time) => write!(f, "DELAYUS{time}"),
             Action::WaitForHigh(pin) => {
                 write!(f, "WAITFORHIGH{pin}")
             }
@@ -60,6 +557,46 @@ impl Display for Action {
             Action::SetPullDown(pin) => {
                 write!(f, "SETPULLDOWN{pin}")
             }
+            Action::Pulse(pin, width, level) => {
+                write!(f, "PULSE{pin}:{level}:{width}US")
+            }
+            Action::Toggle(pin) => write!(f, "TOGGLE{pin}"),
+            Action::SetPwm(pin, freq, duty) => {
+                write!(f, "SETPWM{pin}:{freq}HZ:{duty}")
+            }
+            Action::SetHwPwm(pin, freq, duty) => {
+                write!(f, "SETHWPWM{pin}:{freq}HZ:{duty}")
+            }
+            Action::ServoAngle(pin, angle) => {
+                write!(f, "SERVO{pin}:{angle}DEG")
+            }
+            Action::ServoMove(pin, angle, time) => {
+                write!(f, "SERVO{pin}:{angle}DEG:{time}MS")
+            }
+            Action::I2cWrite(addr, reg, bytes) => {
+                write!(f, "I2CWRITE{addr:#04x}:{reg:#04x}:{}", to_hex(bytes))
+            }
+            Action::I2cRead(addr, reg, len) => {
+                write!(f, "I2CREAD{addr:#04x}:{reg:#04x}:{len}")
+            }
+            Action::SpiTransfer(cs, bytes) => {
+                write!(f, "SPI{cs}:{}", to_hex(bytes))
+            }
+            Action::UartSend(text) => write!(f, "UARTSEND{text:?}"),
+            Action::UartExpect(pattern, time) => {
+                write!(f, "UARTEXPECT{pattern:?}:{time}MS")
+            }
+            Action::ReadSensor(sensor) => write!(f, "READ{sensor}"),
+            Action::WaitForSensor(sensor, quantity, comparison, threshold) => {
+                write!(f, "WAITFOR{sensor}:{quantity}:{comparison}:{threshold}")
+            }
+            Action::ShiftOut(data, clock, latch, bytes, order) => {
+                write!(
+                    f,
+                    "SHIFTOUT{data}:{clock}:{latch}:{order}:{}",
+                    to_hex(bytes)
+                )
+            }
         }
     }
 }
@@ -68,6 +605,17 @@ pub async fn stop_actions(State(appstate): State<AppState>) {
     println!("attempting to stop");
     let _ = log_info(&appstate, "Attempting to Stop");
     appstate.stop_it.store(true, Ordering::Relaxed);
+    cancel_moves(&appstate);
+}
+
+// how often a step that can't check the stop flag itself
+// looks at it, short enough that stopping feels immediate
+const STOP_POLL: Duration = Duration::from_millis(10);
+
+async fn until_stopped(stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        sleep(STOP_POLL).await;
+    }
 }
 
 pub async fn start_actions(
@@ -78,6 +626,7 @@ pub async fn start_actions(
     let _ = log_info(&appstate, "Attempting to start actions");
     let should_loop = input.should_loop.as_deref() == Some("true");
     let stop = appstate.stop_it.clone();
+    let mut stats = RunStats::default();
 
     stop.store(false, Ordering::Relaxed);
 
@@ -124,45 +673,152 @@ pub async fn start_actions(
                     };
                 }
                 Action::Delay(time) => {
-                    sleep(Duration::from_millis(*time as u64)).await
+                    let requested = Duration::from_millis(*time as u64);
+                    let start = Instant::now();
+                    sleep(requested).await;
+                    stats.record(requested, start.elapsed());
                 }
-                Action::WaitForHigh(pin) => loop {
-                    let gpio = appstate.gpio.lock().unwrap();
-                    match gpio.get_level(*pin) {
-                        Ok(level) => {
-                            if let PinLevel::High = level {
-                                println!("got HIGH signal: GPIO {pin}");
-                                break;
-                            }
+                Action::DelayUs(time) => {
+                    let requested = Duration::from_micros(*time as u64);
+                    let actual = if (*time as u64) <= PRECISE_DELAY_MAX_US {
+                        appstate.rt.delay(requested).await
+                    } else {
+                        let start = Instant::now();
+                        sleep(requested).await;
+                        start.elapsed()
+                    };
+                    stats.record(requested, actual);
+                }
+                Action::WaitForHigh(pin) | Action::WaitForLow(pin) => {
+                    let level = match i {
+                        Action::WaitForHigh(_) => PinLevel::High,
+                        _ => PinLevel::Low,
+                    };
+
+                    match wait_for_level(&appstate, *pin, level, &stop).await {
+                        Ok(Some(_)) => {
+                            println!("got {level} signal: GPIO {pin}");
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::SetPullUp(pin) => {
+                    let mut gpio = appstate.gpio.lock().unwrap();
+                    match gpio.set_pull_type(*pin, PullType::Up) {
+                        Ok(_) => {
+                            println!("set pullup: GPIO {pin}");
                         }
                         Err(e) => {
                             let _ = log_error(&appstate, e);
                             break;
                         }
+                    };
+                }
+                Action::SetPullDown(pin) => {
+                    let mut gpio = appstate.gpio.lock().unwrap();
+                    match gpio.set_pull_type(*pin, PullType::Down) {
+                        Ok(_) => {
+                            println!("set pulldown: GPIO {pin}");
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::Pulse(pin, width, level) => {
+                    let (pin, level) = (*pin, *level);
+                    let requested = Duration::from_micros(*width as u64);
+                    let gpio = appstate.gpio.clone();
+                    let spin = appstate.rt.spin();
+
+                    // both edges come from the rt thread so the width
+                    // doesn't depend on tokio. the direction is set up
+                    // front and the edges are bare register writes,
+                    // set_level would print between them
+                    let pulse = appstate
+                        .rt
+                        .run(move || {
+                            let mut gpio = gpio.lock().unwrap();
+                            gpio.set_direction(pin, PinDirection::Output)?;
+                            let (active, idle) = match level {
+                                PinLevel::High => (1 << pin, 0),
+                                PinLevel::Low => (0, 1 << pin),
+                            };
+
+                            gpio.write_levels(active, idle)?;
+                            let start = Instant::now();
+                            precise_sleep(requested, spin);
+                            gpio.write_levels(idle, active)?;
+                            let actual = start.elapsed();
+
+                            gpio.record_level(pin, level.inverted());
+                            Ok::<_, GpioError>(actual)
+                        })
+                        .await;
+
+                    match pulse {
+                        Some(Ok(actual)) => {
+                            println!("pulse {level}: GPIO {pin}");
+                            stats.record(requested, actual);
+                        }
+                        Some(Err(e)) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                        None => {
+                            let _ = log_error(&appstate, "rt runner stopped");
+                            break;
+                        }
                     }
-                    drop(gpio);
-                },
-                Action::WaitForLow(pin) => loop {
-                    let gpio = appstate.gpio.lock().unwrap();
-                    match gpio.get_level(*pin) {
+                }
+                Action::Toggle(pin) => {
+                    let mut gpio = appstate.gpio.lock().unwrap();
+                    match gpio.toggle(*pin) {
                         Ok(level) => {
-                            if let PinLevel::Low = level {
-                                println!("got LOW signal: GPIO {pin}");
-                                break;
-                            }
+                            println!("toggle {level}: GPIO {pin}");
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::SetPwm(pin, freq, duty) => {
+                    let result = match freq {
+                        0 => appstate.pwm.stop(*pin).map(|_| ()),
+                        _ => appstate.pwm.set(*pin, *freq, *duty),
+                    };
+
+                    match result {
+                        Ok(_) => {
+                            println!("pwm {freq}Hz {duty}%: GPIO {pin}");
                         }
                         Err(e) => {
                             let _ = log_error(&appstate, e);
                             break;
                         }
+                    };
+                }
+                Action::SetHwPwm(pin, freq, duty) => {
+                    if *freq != 0 {
+                        let _ = appstate.pwm.stop(*pin);
                     }
-                    drop(gpio);
-                },
-                Action::SetPullUp(pin) => {
+
+                    let mut hwpwm = appstate.hwpwm.lock().unwrap();
                     let mut gpio = appstate.gpio.lock().unwrap();
-                    match gpio.set_pull_type(*pin, PullType::Up) {
+                    let result = match freq {
+                        0 => hwpwm.stop(&mut gpio, *pin),
+                        _ => hwpwm.set(&mut gpio, *pin, *freq, *duty),
+                    };
+
+                    match result {
                         Ok(_) => {
-                            println!("set pullup: GPIO {pin}");
+                            println!("hw pwm {freq}Hz {duty}%: GPIO {pin}");
                         }
                         Err(e) => {
                             let _ = log_error(&appstate, e);
@@ -170,11 +826,182 @@ pub async fn start_actions(
                         }
                     };
                 }
-                Action::SetPullDown(pin) => {
-                    let mut gpio = appstate.gpio.lock().unwrap();
-                    match gpio.set_pull_type(*pin, PullType::Down) {
+                Action::ServoAngle(pin, angle)
+                | Action::ServoMove(pin, angle, _) => {
+                    let time = match i {
+                        Action::ServoMove(_, _, time) => *time,
+                        _ => 0,
+                    };
+                    let duration = Duration::from_millis(time as u64);
+
+                    match move_servo(&appstate, *pin, *angle, duration).await {
                         Ok(_) => {
-                            println!("set pulldown: GPIO {pin}");
+                            println!("servo {angle} deg: GPIO {pin}");
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::I2cWrite(addr, reg, bytes) => {
+                    let result =
+                        LinuxI2c::open(DEFAULT_I2C_BUS).and_then(|mut bus| {
+                            write_register(&mut bus, *addr, *reg, bytes)
+                        });
+
+                    match result {
+                        Ok(_) => {
+                            println!("i2c write {addr:#04x}:{reg:#04x}");
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::SpiTransfer(cs, bytes) => {
+                    let result = Spidev::open(
+                        DEFAULT_SPI_BUS,
+                        *cs,
+                        SpiConfig::default(),
+                    )
+                    .and_then(|spi| spi.transfer(bytes));
+
+                    match result {
+                        Ok(rx) => {
+                            let _ = log_info(
+                                &appstate,
+                                format!(
+                                    "SPI {DEFAULT_SPI_BUS}.{cs} -> {}",
+                                    to_hex(&rx)
+                                ),
+                            );
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::ShiftOut(data, clock, latch, bytes, order) => {
+                    let shifted = run_shift_out(
+                        &appstate,
+                        [*data, *clock, *latch],
+                        bytes.clone(),
+                        *order,
+                        DEFAULT_CLOCK_US,
+                    )
+                    .await;
+
+                    match shifted {
+                        Ok(shift_stats) => {
+                            println!(
+                                "shift out {} bytes: GPIO {data}",
+                                bytes.len()
+                            );
+                            stats.merge(shift_stats);
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::ReadSensor(sensor) => {
+                    match read_sensor(&appstate, sensor).await {
+                        Ok(reading) => {
+                            let _ = log_info(
+                                &appstate,
+                                format!("{sensor}: {reading}"),
+                            );
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::WaitForSensor(
+                    sensor,
+                    quantity,
+                    comparison,
+                    threshold,
+                ) => {
+                    let waited = wait_for_sensor(
+                        &appstate,
+                        sensor,
+                        *quantity,
+                        *comparison,
+                        *threshold,
+                        &stop,
+                    )
+                    .await;
+
+                    match waited {
+                        Ok(Some(reading)) => {
+                            let _ = log_info(
+                                &appstate,
+                                format!("{sensor}: {reading}"),
+                            );
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::UartSend(text) => {
+                    match appstate.uart.send(text.as_bytes()) {
+                        Ok(_) => {
+                            println!("uart send {text:?}");
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::UartExpect(pattern, time) => {
+                    let limit = Duration::from_millis(*time as u64);
+
+                    let result = tokio::select! {
+                        result = appstate.uart.expect(pattern, limit) => result,
+                        _ = until_stopped(&stop) => break,
+                    };
+
+                    match result {
+                        Ok(seen) => {
+                            let _ = log_info(
+                                &appstate,
+                                format!(
+                                    "UART matched {}",
+                                    escape_html(&format!("{:?}", seen))
+                                ),
+                            );
+                        }
+                        Err(e) => {
+                            let _ = log_error(&appstate, e);
+                            break;
+                        }
+                    };
+                }
+                Action::I2cRead(addr, reg, len) => {
+                    let result =
+                        LinuxI2c::open(DEFAULT_I2C_BUS).and_then(|mut bus| {
+                            read_register(&mut bus, *addr, *reg, *len as usize)
+                        });
+
+                    match result {
+                        Ok(bytes) => {
+                            let _ = log_info(
+                                &appstate,
+                                format!(
+                                    "I2C {addr:#04x}:{reg:#04x} -> {}",
+                                    to_hex(&bytes)
+                                ),
+                            );
                         }
                         Err(e) => {
                             let _ = log_error(&appstate, e);
@@ -194,6 +1021,11 @@ pub async fn start_actions(
             break;
         }
     }
+
+    if let Some(summary) = stats.summary() {
+        println!("{summary}");
+        let _ = log_info(&appstate, summary);
+    }
 }
 
 pub async fn delete_action(
@@ -227,51 +1059,20 @@ pub async fn add_action(
     State(appstate): State<AppState>,
     Form(input): Form<ActionForm>,
 ) -> Html<String> {
-    let (action, display_text) = match input.action_type.as_str() {
-        // add bounds for adding actions
-        // gpio pins should be between 0-27.
-        "set-high" => (
-            Action::SetHigh(input.value),
-            format!("GPIO:{} Set High", input.value),
-        ),
-        "set-low" => (
-            Action::SetLow(input.value),
-            format!("GPIO:{} Set Low", input.value),
-        ),
-        "delay" => (
-            Action::Delay(input.value),
-            format!("Delay {}ms", input.value),
-        ),
-        "wait-for-high" => (
-            Action::WaitForHigh(input.value),
-            format!("Wait For HIGH GPIO:{}", input.value),
-        ),
-        "wait-for-low" => (
-            Action::WaitForLow(input.value),
-            format!("Wait For LOW GPIO:{}", input.value),
-        ),
-        "set-pull-up" => (
-            Action::SetPullUp(input.value),
-            format!("GPIO:{} Pull-Up", input.value),
-        ),
-        "set-pull-down" => (
-            Action::SetPullDown(input.value),
-            format!("GPIO:{} Pull-Down", input.value),
-        ),
-        _ => {
-            return {
-                let _ = log_error(
-                    &appstate,
-                    format!(
-                        "Not a vnotalid action: {}",
-                        input.action_type.as_str()
-                    ),
-                );
-                Html("put this in a log somewhere".to_string())
-            };
-        }
+    let action = match Action::from_form(&input) {
+        Ok(action) => action,
+        Err(e) => return form_error(&appstate, e),
+    };
+
+    let validated = {
+        let gpio = appstate.gpio.lock().unwrap();
+        action.validate(&gpio)
     };
 
+    if let Err(e) = validated {
+        return form_error(&appstate, e);
+    }
+
     let mut actions = appstate.actions.lock().unwrap();
     let _ = log_info(&appstate, format!("Adding Action: {}", action));
     actions.push(action.clone());
@@ -288,6 +1089,7 @@ pub async fn add_action(
         Ok(_) => println!("adding action, config save"),
     };
 
+    // clear out any previous error in the form
     Html(format!(
         r#"<div class="pin-item" 
         hx-delete="/delete-action/{}" 
@@ -295,8 +1097,20 @@ pub async fn add_action(
         hx-swap="outerHTML">
             <span class="pin-number">{}</span>
             <span class="pin-delete">DELETE</span>
-        </div>"#,
-        index, display_text
+        </div>
+        <div id="action-error" class="form-error" hx-swap-oob="true"></div>"#,
+        index,
+        action.display_text()
+    ))
+}
+
+// swaps the error into the add action form
+// instead of appending it to the queue
+fn form_error(appstate: &AppState, error: ActionError) -> Html<String> {
+    let _ = log_error(appstate, &error);
+    Html(format!(
+        r#"<div id="action-error" class="form-error" hx-swap-oob="true">{}</div>"#,
+        error
     ))
 }
 
@@ -305,30 +1119,6 @@ pub async fn get_actions(State(appstate): State<AppState>) -> Html<String> {
     let mut html = String::new();
 
     for (i, action) in actions.iter().enumerate() {
-        let display_text = match action {
-            Action::SetHigh(pin) => {
-                format!("GPIO:{} Set High", pin)
-            }
-            Action::SetLow(pin) => {
-                format!("GPIO:{} Set Low", pin)
-            }
-            Action::Delay(time) => {
-                format!("Delay {}ms", time)
-            }
-            Action::WaitForHigh(pin) => {
-                format!("Wait For HIGH GPIO:{}", pin)
-            }
-            Action::WaitForLow(pin) => {
-                format!("Wait For LOW GPIO:{}", pin)
-            }
-            Action::SetPullUp(pin) => {
-                format!("GPIO:{} Pull-Up", pin)
-            }
-            Action::SetPullDown(pin) => {
-                format!("GPIO:{} Pull-Down", pin)
-            }
-        };
-
         html.push_str(&format!(
             r#"<div class="pin-item" 
             hx-delete="/delete-action/{}" 
@@ -337,7 +1127,8 @@ pub async fn get_actions(State(appstate): State<AppState>) -> Html<String> {
                 <span class="pin-number">{}</span>
                 <span class="pin-delete">DELETE</span>
             </div>"#,
-            i, display_text
+            i,
+            action.display_text()
         ));
     }
 
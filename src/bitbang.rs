@@ -0,0 +1,352 @@
+use crate::{
+    errors::GpioError,
+    gpio::{Gpio, PinLevel},
+    hex::parse_bytes,
+    logger::{log_error, log_info},
+    names::deserialize_pin,
+    pwm::pins_changed,
+    timing::{precise_sleep, RunStats, PRECISE_DELAY_MAX_US},
+    AppState,
+};
+
+use axum::{extract::State, response::Response, Form};
+use serde::{Deserialize, Serialize};
+use std::{
+    hint::spin_loop,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// 74HC595 is happy well past this,
+// it's more about what the pi can hold steady
+pub const DEFAULT_CLOCK_US: u32 = 1;
+
+// WS2812 bit timings in ns, high time for a 0 and a 1
+// out of a 1250ns bit, anything over 50us low latches
+const WS2812_T0H: u64 = 400;
+const WS2812_T1H: u64 = 800;
+const WS2812_BIT: u64 = 1250;
+const WS2812_SLOP: u64 = 150;
+const WS2812_RESET_US: u64 = 300;
+
+// the frame holds the gpio lock and the rt thread like a shift
+// out does, so it gets the same precise delay budget. 666 pixels
+pub const MAX_WS2812_PIXELS: usize =
+    (PRECISE_DELAY_MAX_US * 1000 / (24 * WS2812_BIT)) as usize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+impl BitOrder {
+    fn bits(self, byte: u8) -> impl Iterator<Item = bool> {
+        (0..8).map(move |i| match self {
+            BitOrder::MsbFirst => byte & (0x80 >> i) != 0,
+            BitOrder::LsbFirst => byte & (1 << i) != 0,
+        })
+    }
+}
+
+impl FromStr for BitOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "msb" | "msb-first" => Ok(BitOrder::MsbFirst),
+            "lsb" | "lsb-first" => Ok(BitOrder::LsbFirst),
+            other => Err(format!("Invalid bit order `{other}`")),
+        }
+    }
+}
+
+impl std::fmt::Display for BitOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BitOrder::MsbFirst => write!(f, "MSB"),
+            BitOrder::LsbFirst => write!(f, "LSB"),
+        }
+    }
+}
+
+// two half periods a bit plus the latch pulse
+pub fn shift_out_duration(bytes: usize, clock_us: u32) -> Duration {
+    Duration::from_micros(((bytes as u64 * 8 + 1) * 2) * clock_us as u64)
+}
+
+// the whole transfer holds the gpio lock and the
+// rt thread so keep it within a precise delay
+pub fn check_shift_out(
+    pins: [i32; 3],
+    bytes: usize,
+    clock_us: u32,
+) -> Result<(), String> {
+    let [data, clock, latch] = pins;
+    if data == clock || data == latch || clock == latch {
+        return Err("data, clock and latch need their own pins".to_string());
+    }
+
+    if bytes == 0 || clock_us == 0 {
+        return Err(
+            "need at least one byte and a clock of 1us or more".to_string()
+        );
+    }
+
+    let total = shift_out_duration(bytes, clock_us);
+    if total > Duration::from_micros(PRECISE_DELAY_MAX_US) {
+        return Err(format!(
+            "{bytes} bytes at {clock_us}us takes {}us, \
+             limit is {PRECISE_DELAY_MAX_US}us",
+            total.as_micros()
+        ));
+    }
+
+    Ok(())
+}
+
+// 74HC595 style, data is sampled on the clock's rising
+// edge and the outputs update when latch goes high
+#[allow(clippy::too_many_arguments)]
+pub fn shift_out(
+    gpio: &mut Gpio,
+    data: i32,
+    clock: i32,
+    latch: i32,
+    bytes: &[u8],
+    order: BitOrder,
+    clock_us: u32,
+    spin: Duration,
+) -> Result<RunStats, GpioError> {
+    check_shift_out([data, clock, latch], bytes.len(), clock_us)
+        .map_err(GpioError::BitBang)?;
+
+    // the chip wants voltages, so the pins' polarity is ignored.
+    // also puts all three into output mode
+    gpio.set_physical_level(clock, PinLevel::Low)?;
+    gpio.set_physical_level(latch, PinLevel::Low)?;
+    gpio.set_physical_level(data, PinLevel::Low)?;
+
+    let half = Duration::from_micros(clock_us as u64);
+    let mut stats = RunStats::default();
+    let mut wait = || stats.record(half, precise_sleep(half, spin));
+
+    let mut last = false;
+    for byte in bytes {
+        for bit in order.bits(*byte) {
+            match bit {
+                true => gpio.write_physical_levels(1 << data, 0)?,
+                false => gpio.write_physical_levels(0, 1 << data)?,
+            }
+            last = bit;
+            wait();
+            gpio.write_physical_levels(1 << clock, 0)?;
+            wait();
+            gpio.write_physical_levels(0, 1 << clock)?;
+        }
+    }
+
+    gpio.write_physical_levels(1 << latch, 0)?;
+    wait();
+    gpio.write_physical_levels(0, 1 << latch)?;
+
+    // keep the pin panel in line with what's on the wire
+    let level = if last { PinLevel::High } else { PinLevel::Low };
+    gpio.set_physical_level(data, level)?;
+
+    Ok(stats)
+}
+
+fn spin_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        spin_loop();
+    }
+}
+
+// sub microsecond timing from userspace, checks every
+// bit afterwards and fails if we got preempted mid frame
+pub fn ws2812_write(
+    gpio: &mut Gpio,
+    pin: i32,
+    grb: &[u8],
+) -> Result<(), GpioError> {
+    if grb.is_empty() || !grb.len().is_multiple_of(3) {
+        return Err(GpioError::BitBang(
+            "ws2812 needs 3 bytes per pixel".to_string(),
+        ));
+    }
+
+    if grb.len() / 3 > MAX_WS2812_PIXELS {
+        return Err(GpioError::BitBang(format!(
+            "{} pixels is over the {MAX_WS2812_PIXELS} one frame can hold",
+            grb.len() / 3
+        )));
+    }
+
+    // physical like shift_out, the strip doesn't know about polarity
+    gpio.set_physical_level(pin, PinLevel::Low)?;
+    let mask = 1 << pin;
+    let mut worst = Duration::ZERO;
+
+    for byte in grb {
+        for bit in BitOrder::MsbFirst.bits(*byte) {
+            let high = if bit { WS2812_T1H } else { WS2812_T0H };
+
+            let start = Instant::now();
+            gpio.write_physical_levels(mask, 0)?;
+            spin_until(start + Duration::from_nanos(high));
+            gpio.write_physical_levels(0, mask)?;
+            let held = start.elapsed();
+            spin_until(start + Duration::from_nanos(WS2812_BIT));
+
+            let late = held.saturating_sub(Duration::from_nanos(high));
+            worst = worst.max(late);
+        }
+    }
+
+    // let the strip latch before anyone else writes
+    spin_until(Instant::now() + Duration::from_micros(WS2812_RESET_US));
+
+    if worst > Duration::from_nanos(WS2812_SLOP) {
+        return Err(GpioError::BitBang(format!(
+            "ws2812 bit ran {}ns long, try realtime timing",
+            worst.as_nanos()
+        )));
+    }
+
+    Ok(())
+}
+
+// "ff0000 00ff00" as rgb, sent out as grb
+pub fn parse_pixels(s: &str) -> Result<Vec<u8>, String> {
+    let rgb = parse_bytes(s)?;
+    if rgb.is_empty() || !rgb.len().is_multiple_of(3) {
+        return Err("pixels need 3 bytes each".to_string());
+    }
+
+    Ok(rgb.chunks(3).flat_map(|px| [px[1], px[0], px[2]]).collect())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShiftOutForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub data_pin: i32,
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub clock_pin: i32,
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub latch_pin: i32,
+    pub bytes: String,
+    pub bit_order: BitOrder,
+    pub clock_us: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ws2812Form {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    pub pixels: String,
+}
+
+// on the rt thread so the bits aren't
+// spread across tokio's scheduling
+pub async fn run_shift_out(
+    appstate: &AppState,
+    pins: [i32; 3],
+    bytes: Vec<u8>,
+    order: BitOrder,
+    clock_us: u32,
+) -> Result<RunStats, GpioError> {
+    // soft pwm would fight over the pins
+    for pin in pins {
+        let _ = appstate.pwm.stop(pin);
+    }
+
+    let gpio = appstate.gpio.clone();
+    let spin = appstate.rt.spin();
+    let [data, clock, latch] = pins;
+
+    appstate
+        .rt
+        .run(move || {
+            let mut gpio = gpio.lock().unwrap();
+            shift_out(
+                &mut gpio, data, clock, latch, &bytes, order, clock_us, spin,
+            )
+        })
+        .await
+        .unwrap_or_else(|| {
+            Err(GpioError::BitBang("rt runner stopped".to_string()))
+        })
+}
+
+pub async fn shift_out_bytes(
+    State(appstate): State<AppState>,
+    Form(input): Form<ShiftOutForm>,
+) -> Response {
+    let clock_us = input.clock_us.unwrap_or(DEFAULT_CLOCK_US);
+    let bytes = match parse_bytes(&input.bytes) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return pins_changed(log_error(
+                &appstate,
+                format!("failed to shift out: {e}"),
+            ));
+        }
+    };
+
+    let pins = [input.data_pin, input.clock_pin, input.latch_pin];
+    let count = bytes.len();
+
+    pins_changed(
+        match run_shift_out(&appstate, pins, bytes, input.bit_order, clock_us)
+            .await
+        {
+            Ok(stats) => log_info(
+                &appstate,
+                format!(
+                    "Shifted {count} bytes {} out GPIO {}. {}",
+                    input.bit_order,
+                    input.data_pin,
+                    stats.summary().unwrap_or_default()
+                ),
+            ),
+            Err(e) => log_error(&appstate, format!("failed to shift out: {e}")),
+        },
+    )
+}
+
+pub async fn write_ws2812(
+    State(appstate): State<AppState>,
+    Form(input): Form<Ws2812Form>,
+) -> Response {
+    let grb = match parse_pixels(&input.pixels) {
+        Ok(grb) => grb,
+        Err(e) => {
+            return pins_changed(log_error(
+                &appstate,
+                format!("failed to write ws2812: {e}"),
+            ));
+        }
+    };
+
+    let _ = appstate.pwm.stop(input.pin);
+    let gpio = appstate.gpio.clone();
+    let pin = input.pin;
+    let count = grb.len() / 3;
+
+    let result = appstate
+        .rt
+        .run(move || ws2812_write(&mut gpio.lock().unwrap(), pin, &grb))
+        .await
+        .unwrap_or_else(|| {
+            Err(GpioError::BitBang("rt runner stopped".to_string()))
+        });
+
+    pins_changed(match result {
+        Ok(_) => {
+            log_info(&appstate, format!("Wrote {count} pixels to GPIO {pin}"))
+        }
+        Err(e) => log_error(&appstate, format!("failed to write ws2812: {e}")),
+    })
+}
@@ -0,0 +1,336 @@
+use crate::gpio::Backend;
+
+use std::{
+    env, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const DEFAULT_BIND: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 3000;
+
+const USAGE: &str = "\
+Usage: pipin [OPTIONS] [PORT]
+
+Options:
+  -b, --bind <ADDR>       address to listen on, default 0.0.0.0
+                          [env: PIPIN_BIND]
+  -p, --port <PORT>       port to listen on, default 3000 [env: PIPIN_PORT]
+  -c, --config <PATH>     config file [env: PIPIN_CONFIG], default is the first
+                          of ./config.json, ~/.config/pipin/config.json and
+                          /etc/pipin/config.json that exists
+      --profile <NAME>    start on a profile other than default
+                          [env: PIPIN_PROFILE]
+      --backend <NAME>    gpiomem (default) or mem, /dev/mem needs root
+                          [env: PIPIN_BACKEND]
+      --auto-setup        run Setup on boot [env: PIPIN_AUTO_SETUP]
+      --log-file <PATH>   append the log panel to a file [env: PIPIN_LOG_FILE]
+      --read-only         serve the ui but refuse anything that changes state
+                          [env: PIPIN_READ_ONLY]
+  -h, --help              print this and exit
+  -V, --version           print the version and exit
+
+Flags win over environment variables. A bare PORT is the same as --port.";
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub config: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub backend: Backend,
+    pub auto_setup: bool,
+    pub log_file: Option<PathBuf>,
+    pub read_only: bool,
+}
+
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+pub fn usage() -> &'static str {
+    USAGE
+}
+
+pub fn version() -> String {
+    format!("pipin {}", env!("CARGO_PKG_VERSION"))
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value `{value}` for {name}: {e}"))
+}
+
+// "1", "true", "yes" and "on", anything else is off
+fn env_flag(value: &str) -> bool {
+    matches!(
+        value.trim().to_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
+// where an on/off option was turned on, a flag
+// beats the environment when the two disagree
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum Switch {
+    #[default]
+    Off,
+    Env,
+    Flag,
+}
+
+impl Switch {
+    fn on(self) -> bool {
+        self != Switch::Off
+    }
+}
+
+// what was actually asked for, before defaults,
+// so conflicts can tell "unset" from "default"
+struct Raw {
+    bind: Option<String>,
+    port: Option<String>,
+    positional_port: Option<String>,
+    config: Option<String>,
+    profile: Option<String>,
+    backend: Option<String>,
+    auto_setup: Switch,
+    log_file: Option<String>,
+    read_only: Switch,
+}
+
+fn from_env(lookup: &impl Fn(&str) -> Option<String>) -> Raw {
+    let flag = |key: &str| match lookup(key).is_some_and(|v| env_flag(&v)) {
+        true => Switch::Env,
+        false => Switch::Off,
+    };
+
+    Raw {
+        bind: lookup("PIPIN_BIND"),
+        port: lookup("PIPIN_PORT"),
+        positional_port: None,
+        config: lookup("PIPIN_CONFIG"),
+        profile: lookup("PIPIN_PROFILE"),
+        backend: lookup("PIPIN_BACKEND"),
+        auto_setup: flag("PIPIN_AUTO_SETUP"),
+        log_file: lookup("PIPIN_LOG_FILE"),
+        read_only: flag("PIPIN_READ_ONLY"),
+    }
+}
+
+pub fn parse(
+    args: impl IntoIterator<Item = String>,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Command, String> {
+    let mut raw = from_env(&lookup);
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // --port=3000 as well as --port 3000
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+
+        let mut value = |name: &str| {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{name} needs a value"))
+        };
+
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-b" | "--bind" => raw.bind = Some(value("--bind")?),
+            "-p" | "--port" => raw.port = Some(value("--port")?),
+            "-c" | "--config" => raw.config = Some(value("--config")?),
+            "--profile" => raw.profile = Some(value("--profile")?),
+            "--backend" => raw.backend = Some(value("--backend")?),
+            "--log-file" => raw.log_file = Some(value("--log-file")?),
+            "--auto-setup" => raw.auto_setup = Switch::Flag,
+            "--read-only" => raw.read_only = Switch::Flag,
+            other if other.starts_with('-') => {
+                return Err(format!("unknown option `{other}`"));
+            }
+            _ if raw.positional_port.is_none() => {
+                raw.positional_port = Some(arg);
+            }
+            _ => return Err(format!("unexpected argument `{arg}`")),
+        }
+    }
+
+    validate(raw).map(Command::Run)
+}
+
+// either file may not exist yet, so the directory is
+// resolved instead and the name put back on
+fn canonical(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(dir), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    canonical(a) == canonical(b)
+}
+
+fn validate(raw: Raw) -> Result<Options, String> {
+    // the old `pipin 8080` still works, just not alongside --port
+    let port = match (raw.positional_port, raw.port) {
+        (Some(positional), Some(flag)) if positional != flag => {
+            return Err(format!(
+                "port given twice, `{positional}` and --port `{flag}`"
+            ));
+        }
+        (Some(port), _) | (None, Some(port)) => parse_value("port", &port)?,
+        (None, None) => DEFAULT_PORT,
+    };
+
+    if port == 0 {
+        return Err("port must be between 1 and 65535".to_string());
+    }
+
+    // auto setup applies the startup states, which read only
+    // is meant to rule out. a flag overrides the environment,
+    // both asked for the same way is a mistake
+    let (auto_setup, read_only) = match (raw.auto_setup, raw.read_only) {
+        (Switch::Flag, Switch::Env) => (true, false),
+        (Switch::Env, Switch::Flag) => (false, true),
+        (auto_setup, read_only) if auto_setup.on() && read_only.on() => {
+            return Err(
+                "--read-only and --auto-setup can't be combined".to_string()
+            );
+        }
+        (auto_setup, read_only) => (auto_setup.on(), read_only.on()),
+    };
+
+    if let (Some(log_file), Some(config)) = (&raw.log_file, &raw.config) {
+        if same_file(Path::new(log_file), Path::new(config)) {
+            return Err(
+                "--log-file and --config point at the same file".to_string()
+            );
+        }
+    }
+
+    let bind = raw.bind.as_deref().unwrap_or(DEFAULT_BIND);
+
+    Ok(Options {
+        bind: parse_value("bind", bind)?,
+        port,
+        config: raw.config.map(PathBuf::from),
+        profile: raw.profile,
+        backend: match raw.backend {
+            Some(backend) => backend.parse()?,
+            None => Backend::GpioMem,
+        },
+        auto_setup,
+        log_file: raw.log_file.map(PathBuf::from),
+        read_only,
+    })
+}
+
+pub fn from_env_args() -> Result<Command, String> {
+    parse(env::args().skip(1), |key| env::var(key).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn run(args: &[&str], env: &[(&str, &str)]) -> Result<Options, String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let args = args.iter().map(|arg| arg.to_string());
+
+        match parse(args, |key| env.get(key).cloned())? {
+            Command::Run(options) => Ok(options),
+            _ => Err("not a run".to_string()),
+        }
+    }
+
+    #[test]
+    fn defaults_with_nothing_set() {
+        let options = run(&[], &[]).unwrap();
+
+        assert_eq!(options.port, DEFAULT_PORT);
+        assert!(!options.auto_setup);
+        assert!(!options.read_only);
+    }
+
+    #[test]
+    fn flags_beat_the_environment() {
+        let env = [("PIPIN_PORT", "4000"), ("PIPIN_PROFILE", "bench")];
+        let options = run(&["--port", "5000", "--profile=lab"], &env).unwrap();
+
+        assert_eq!(options.port, 5000);
+        assert_eq!(options.profile.as_deref(), Some("lab"));
+        assert_eq!(run(&[], &env).unwrap().port, 4000);
+    }
+
+    #[test]
+    fn env_switches_take_the_usual_spellings() {
+        for on in ["1", "true", "YES", "on"] {
+            let options = run(&[], &[("PIPIN_READ_ONLY", on)]).unwrap();
+            assert!(options.read_only, "{on}");
+        }
+
+        let options = run(&[], &[("PIPIN_READ_ONLY", "0")]).unwrap();
+        assert!(!options.read_only);
+    }
+
+    #[test]
+    fn read_only_flag_overrides_auto_setup_env() {
+        let options =
+            run(&["--read-only"], &[("PIPIN_AUTO_SETUP", "1")]).unwrap();
+
+        assert!(options.read_only);
+        assert!(!options.auto_setup);
+    }
+
+    #[test]
+    fn auto_setup_flag_overrides_read_only_env() {
+        let options =
+            run(&["--auto-setup"], &[("PIPIN_READ_ONLY", "1")]).unwrap();
+
+        assert!(options.auto_setup);
+        assert!(!options.read_only);
+    }
+
+    #[test]
+    fn read_only_and_auto_setup_asked_the_same_way_conflict() {
+        assert!(run(&["--read-only", "--auto-setup"], &[]).is_err());
+
+        let env = [("PIPIN_READ_ONLY", "1"), ("PIPIN_AUTO_SETUP", "1")];
+        assert!(run(&[], &env).is_err());
+    }
+
+    #[test]
+    fn positional_port_has_to_agree_with_the_flag() {
+        assert_eq!(run(&["8080"], &[]).unwrap().port, 8080);
+        assert_eq!(run(&["8080", "-p", "8080"], &[]).unwrap().port, 8080);
+        assert!(run(&["8080", "-p", "9090"], &[]).is_err());
+        assert!(run(&["0"], &[]).is_err());
+    }
+}
@@ -1,51 +1,513 @@
-use crate::actions::Action;
+use crate::{
+    actions::Action,
+    debounce::DebounceConfig,
+    format::Format,
+    logger::{escape_html, log_pending_error, log_pending_info},
+    migrate::{migrate, CONFIG_VERSION},
+    names::resolve_config_pins,
+    sensors::SensorPoll,
+    servo::ServoConfig,
+    shutdown::ShutdownPolicy,
+    startup::StartupConfig,
+    timing::TimingConfig,
+};
 
 use serde::{Deserialize, Serialize};
-use std::{fs, io};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock, RwLock},
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
+    // see migrate.rs, missing means a file from before versioning
+    #[serde(default)]
+    pub version: u32,
     pub actions: Vec<Action>,
+    #[serde(default)]
+    pub timing: TimingConfig,
+    #[serde(default)]
+    pub servos: Vec<ServoConfig>,
+    #[serde(default)]
+    pub sensor_polls: Vec<SensorPoll>,
+    #[serde(default)]
+    pub debounce: Vec<DebounceConfig>,
+    #[serde(default)]
+    pub inverted_pins: Vec<i32>,
+    #[serde(default)]
+    pub pin_names: BTreeMap<i32, String>,
+    #[serde(default)]
+    pub startup: StartupConfig,
+    #[serde(default)]
+    pub shutdown: Vec<ShutdownPolicy>,
+
+    // anything from a newer pipin or a hand edit
+    // we don't know, written back as it was
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: CONFIG_VERSION,
+            actions: Vec::new(),
+            timing: TimingConfig::default(),
+            servos: Vec::new(),
+            sensor_polls: Vec::new(),
+            debounce: Vec::new(),
+            inverted_pins: Vec::new(),
+            pin_names: BTreeMap::new(),
+            startup: StartupConfig::default(),
+            shutdown: Vec::new(),
+            extra: Map::new(),
+        }
+    }
 }
 
 const DEFAULT_CONF_NAME: &str = "config";
+const SYSTEM_CONF_DIR: &str = "/etc/pipin";
+
+// the profile everything else is named after
+pub const DEFAULT_PROFILE: &str = "default";
+
+// set once from the command line, before anything saves
+static BASE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// None is the default profile, i.e. the base file itself
+static PROFILE: RwLock<Option<String>> = RwLock::new(None);
+
+pub fn set_conf_path(path: PathBuf) {
+    let _ = BASE_PATH.set(path);
+}
+
+fn user_conf_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    }
+}
+
+// any of these works, the extension picks the format
+const CONF_EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+// ./config.json from older versions wins so existing setups
+// keep working, then the per-user and system-wide files.
+// with none of them around a fresh json one goes in the user dir
+fn default_conf_path() -> PathBuf {
+    let user = user_conf_dir().map(|dir| dir.join("pipin"));
+    let system = PathBuf::from(SYSTEM_CONF_DIR);
+    let dirs = [Some(PathBuf::from(".")), user.clone(), Some(system.clone())];
+
+    let file_name = |ext: &str| format!("{}.{}", DEFAULT_CONF_NAME, ext);
+    let found = dirs.iter().flatten().find_map(|dir| {
+        CONF_EXTENSIONS
+            .iter()
+            .map(|ext| dir.join(file_name(ext)))
+            .find(|path| path.exists())
+    });
+
+    match found {
+        // keeps the old relative "config.json" for the cwd
+        Some(path) => path
+            .strip_prefix(".")
+            .map(Path::to_path_buf)
+            .unwrap_or(path),
+        None => user.unwrap_or(system).join(file_name("json")),
+    }
+}
+
+pub fn base_conf_path() -> &'static Path {
+    BASE_PATH.get_or_init(default_conf_path)
+}
+
+// config.json -> config.bench.json, next to the base file
+pub fn profile_path(profile: Option<&str>) -> PathBuf {
+    let base = base_conf_path();
+    let Some(profile) = profile else {
+        return base.to_path_buf();
+    };
+
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match base.extension() {
+        Some(ext) => format!("{stem}.{profile}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{profile}"),
+    };
+    base.with_file_name(file_name)
+}
+
+pub fn conf_path() -> PathBuf {
+    profile_path(PROFILE.read().unwrap().as_deref())
+}
+
+pub fn active_profile() -> String {
+    PROFILE
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+}
+
+pub fn check_profile(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "Invalid profile `{}`, use letters, digits, _ and -",
+            escape_html(name)
+        )),
+    }
+}
+
+pub fn set_profile(name: &str) -> Result<(), String> {
+    check_profile(name)?;
+    *PROFILE.write().unwrap() = match name {
+        DEFAULT_PROFILE => None,
+        name => Some(name.to_string()),
+    };
+    Ok(())
+}
+
+// every profile with a file next to the base one
+pub fn profiles() -> Vec<String> {
+    let base = base_conf_path();
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = base.extension().map(|ext| ext.to_string_lossy());
+    let dir = match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut profiles = vec![DEFAULT_PROFILE.to_string()];
+    let Ok(entries) = fs::read_dir(dir) else {
+        return profiles;
+    };
+
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(rest) = file_name.strip_prefix(&format!("{stem}.")) else {
+            continue;
+        };
+        let name = match &ext {
+            Some(ext) => rest.strip_suffix(&format!(".{ext}")),
+            None => Some(rest),
+        };
+
+        if let Some(name) = name.filter(|name| {
+            *name != DEFAULT_PROFILE && check_profile(name).is_ok()
+        }) {
+            profiles.push(name.to_string());
+        }
+    }
+
+    profiles[1..].sort();
+    profiles
+}
+
+// a file that fails to parse is never rotated in, so
+// these are always the last few configs that loaded
+const BACKUPS: usize = 3;
+
+// config.json -> config.json.1, config.json.tmp, ...
+fn with_suffix(path: &Path, suffix: impl fmt::Display) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{suffix}"));
+    PathBuf::from(name)
+}
+
+struct Loaded {
+    config: Config,
+    // the version it was upgraded from, if it was
+    migrated_from: Option<u32>,
+}
+
+fn parse_conf(path: &Path, text: &str) -> io::Result<Loaded> {
+    let mut value = Format::from_path(path).parse(text)?;
+
+    let migrated_from = migrate(&mut value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    resolve_config_pins(&mut value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let config = serde_json::from_value(value)?;
+
+    Ok(Loaded {
+        config,
+        migrated_from,
+    })
+}
+
+fn read_conf(path: &Path) -> io::Result<Loaded> {
+    parse_conf(path, &fs::read_to_string(path)?)
+}
+
+// the config the running state was last built from, or last
+// wrote out. a file that differs from it was edited by hand
+static SYNCED: Mutex<Option<Value>> = Mutex::new(None);
+
+pub fn mark_synced(config: &Config) {
+    *SYNCED.lock().unwrap() = serde_json::to_value(config).ok();
+}
+
+// top level sections someone changed on disk that this
+// save would put back to something else
+fn conflicts(synced: &Value, disk: &Value, new: &Value) -> Vec<String> {
+    let Value::Object(disk) = disk else {
+        return Vec::new();
+    };
+
+    disk.iter()
+        .filter(|(key, value)| {
+            synced.get(key.as_str()) != Some(value)
+                && new.get(key.as_str()) != Some(value)
+        })
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+// the old file stays around as the newest backup
+fn upgrade(path: &Path, loaded: Loaded) -> io::Result<Config> {
+    let Some(from) = loaded.migrated_from else {
+        return Ok(loaded.config);
+    };
+
+    save_conf(&loaded.config)?;
+    log_pending_info(format!(
+        "Upgraded {} from config v{} to v{}, the old one is {}",
+        path.display(),
+        from,
+        CONFIG_VERSION,
+        with_suffix(path, 1).display()
+    ));
+    Ok(loaded.config)
+}
+
+fn rotate_backups(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    // keep a broken file around for a look instead
+    // of quietly writing over it
+    if read_conf(path).is_err() {
+        fs::copy(path, with_suffix(path, "corrupt"))?;
+        return Ok(());
+    }
+
+    for n in (1..BACKUPS).rev() {
+        let from = with_suffix(path, n);
+        if from.exists() {
+            fs::rename(from, with_suffix(path, n + 1))?;
+        }
+    }
+
+    // a link keeps the old contents once the rename lands,
+    // copy for filesystems that can't do links
+    let first = with_suffix(path, 1);
+    if fs::hard_link(path, &first).is_err() {
+        fs::copy(path, &first)?;
+    }
+
+    Ok(())
+}
+
+// temp file, fsync, rename over the old one, so a power cut
+// leaves either the old file or the new one, never half
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, "tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+
+    fs::rename(&tmp, path)?;
+
+    // the rename only sticks once the directory is synced
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
 
 pub fn save_conf(config: &Config) -> io::Result<()> {
-    let file_name = format!("{}.json", DEFAULT_CONF_NAME);
-    let jason = serde_json::to_string_pretty(config)?;
-    fs::write(file_name, jason)?;
+    let path = conf_path();
+    if let Some(dir) = path.parent().filter(|dir| !dir.exists()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    // same format as the file, comments kept where the format allows
+    let value = serde_json::to_value(config)?;
+    let previous = fs::read_to_string(&path).ok();
+    let text = Format::from_path(&path).render(&value, previous.as_deref())?;
+
+    let disk = previous
+        .as_deref()
+        .and_then(|text| parse_conf(&path, text).ok())
+        .and_then(|loaded| serde_json::to_value(loaded.config).ok());
+
+    let mut synced = SYNCED.lock().unwrap();
+    let edited = match (synced.as_ref(), &disk) {
+        (Some(synced), Some(disk)) if synced != disk => {
+            Some(conflicts(synced, disk, &value))
+        }
+        _ => None,
+    };
+
+    // a hand edit that hasn't been picked up yet. the sections
+    // saved here win, the rest of the edit is left for the
+    // watcher to load once this lands
+    if let Some(keys) = edited.as_ref().filter(|keys| !keys.is_empty()) {
+        let kept = with_suffix(&path, "conflict");
+        write_atomic(
+            &kept,
+            previous.as_deref().unwrap_or_default().as_bytes(),
+        )?;
+        log_pending_error(format!(
+            "{} was edited on disk while {} changed here, kept the \
+             change from here, the edit is in {}",
+            path.display(),
+            keys.join(", "),
+            kept.display()
+        ));
+    }
+
+    // the old file is linked in as .1, so it's still
+    // there if the rename below never happens
+    rotate_backups(&path)?;
+    write_atomic(&path, text.as_bytes())?;
+    if edited.is_none() {
+        *synced = Some(value);
+    }
     Ok(())
 }
 
+// the file as it is now if it's not what the running state
+// has, None when it's unchanged, e.g. after our own save.
+// it only counts as synced once it's been applied
+pub fn reload() -> io::Result<Option<Config>> {
+    let path = conf_path();
+    let config = upgrade(&path, read_conf(&path)?)?;
+    let value = serde_json::to_value(&config)?;
+
+    match SYNCED.lock().unwrap().as_ref() == Some(&value) {
+        true => Ok(None),
+        false => Ok(Some(config)),
+    }
+}
+
+// newest backup that still loads, which then gets
+// written back as the config
+fn recover(path: &Path, error: io::Error) -> io::Result<Config> {
+    for n in 1..=BACKUPS {
+        let backup = with_suffix(path, n);
+        let Ok(loaded) = read_conf(&backup) else {
+            continue;
+        };
+
+        log_pending_error(format!(
+            "{} couldn't be loaded ({}), restored {}",
+            path.display(),
+            error,
+            backup.display()
+        ));
+        save_conf(&loaded.config)?;
+        return Ok(loaded.config);
+    }
+
+    log_pending_error(format!(
+        "{} couldn't be loaded ({}) and there's no good backup, \
+         starting empty. the next save keeps the old file as {}",
+        path.display(),
+        error,
+        with_suffix(path, "corrupt").display()
+    ));
+    Err(error)
+}
+
 pub fn load_conf() -> io::Result<Config> {
-    let file_name = format!("{}.json", DEFAULT_CONF_NAME);
-    match fs::read_to_string(file_name) {
-        Ok(json) => {
-            let config = serde_json::from_str(&json)?;
-            Ok(config)
-        }
-        Err(e) => {
-            if e.kind() == io::ErrorKind::NotFound {
-                let default_config = Config {
-                    actions: Vec::new(),
-                };
-                save_conf(&default_config)?;
-                Ok(default_config)
-            } else {
-                Err(e)
-            }
+    let path = conf_path();
+    match read_conf(&path) {
+        Ok(loaded) => upgrade(&path, loaded),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let default_config = Config::default();
+            save_conf(&default_config)?;
+            Ok(default_config)
         }
+        Err(e) => recover(&path, e),
     }
 }
 
+// a sequence or a whole config from somewhere else, the
+// file name only picks the format
+pub fn parse_upload(file_name: &str, text: &str) -> io::Result<Config> {
+    parse_conf(Path::new(file_name), text).map(|loaded| loaded.config)
+}
+
+// any profile's file as it is, nothing gets saved
+pub fn read_profile(profile: &str) -> io::Result<Config> {
+    check_profile(profile)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let name = (profile != DEFAULT_PROFILE).then_some(profile);
+    read_conf(&profile_path(name)).map(|loaded| loaded.config)
+}
+
 pub fn save_actions(actions: &[Action]) -> io::Result<()> {
-    let mut config = match load_conf() {
-        Ok(conf) => conf,
-        Err(_) => Config {
-            actions: Vec::new(),
-        },
-    };
+    let mut config = load_conf().unwrap_or_default();
 
     config.actions = actions.to_vec();
     save_conf(&config)
 }
+
+pub fn save_servos(servos: &[ServoConfig]) -> io::Result<()> {
+    let mut config = load_conf().unwrap_or_default();
+
+    config.servos = servos.to_vec();
+    save_conf(&config)
+}
+
+pub fn save_sensor_polls(polls: &[SensorPoll]) -> io::Result<()> {
+    let mut config = load_conf().unwrap_or_default();
+
+    config.sensor_polls = polls.to_vec();
+    save_conf(&config)
+}
+
+pub fn save_debounce(debounce: &[DebounceConfig]) -> io::Result<()> {
+    let mut config = load_conf().unwrap_or_default();
+
+    config.debounce = debounce.to_vec();
+    save_conf(&config)
+}
+
+pub fn save_inverted_pins(pins: &[i32]) -> io::Result<()> {
+    let mut config = load_conf().unwrap_or_default();
+
+    config.inverted_pins = pins.to_vec();
+    save_conf(&config)
+}
+
+pub fn save_pin_names(names: &BTreeMap<i32, String>) -> io::Result<()> {
+    let mut config = load_conf().unwrap_or_default();
+
+    config.pin_names = names.clone();
+    save_conf(&config)
+}
+
+pub fn save_shutdown(policies: &[ShutdownPolicy]) -> io::Result<()> {
+    let mut config = load_conf().unwrap_or_default();
+
+    config.shutdown = policies.to_vec();
+    save_conf(&config)
+}
@@ -0,0 +1,410 @@
+use crate::{
+    config::save_debounce,
+    errors::GpioError,
+    gpio::PinLevel,
+    logger::{log_error, log_info, trigger_html},
+    names::deserialize_pin,
+    AppState,
+};
+
+use axum::{extract::State, response::Html, Form};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+// how often a debounced pin gets looked at,
+// settle times are rounded up to this
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+// anything longer is a hold, not a bounce
+pub const MAX_SETTLE_MS: u32 = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DebounceConfig {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    // how long a level has to hold before we believe it
+    pub settle_ms: u32,
+}
+
+impl DebounceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0..=27).contains(&self.pin) {
+            return Err(GpioError::InvalidPin(self.pin).to_string());
+        }
+
+        if self.settle_ms > MAX_SETTLE_MS {
+            return Err(format!(
+                "settle time must be at most {MAX_SETTLE_MS}ms"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn debounce_map(configs: Vec<DebounceConfig>) -> HashMap<i32, u32> {
+    configs
+        .into_iter()
+        .map(|config| (config.pin, config.settle_ms))
+        .collect()
+}
+
+// level sampling filter, a new level only counts once every
+// sample for settle has agreed. doesn't touch the hardware
+// so it can be fed made up samples just as well
+pub struct Debouncer {
+    settle: Duration,
+    stable: Option<PinLevel>,
+    candidate: Option<(PinLevel, Instant)>,
+}
+
+impl Debouncer {
+    pub fn new(settle: Duration) -> Self {
+        Debouncer {
+            settle,
+            stable: None,
+            candidate: None,
+        }
+    }
+
+    // returns the filtered level, None until the
+    // first level has settled
+    pub fn update(&mut self, raw: PinLevel, now: Instant) -> Option<PinLevel> {
+        match self.candidate {
+            Some((level, since)) if level == raw => {
+                if now.duration_since(since) >= self.settle {
+                    self.stable = Some(level);
+                }
+            }
+            // bounced, start timing again
+            _ => self.candidate = Some((raw, now)),
+        }
+
+        if self.settle.is_zero() {
+            self.stable = Some(raw);
+        }
+
+        self.stable
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Edge::Rising => write!(f, "rising"),
+            Edge::Falling => write!(f, "falling"),
+        }
+    }
+}
+
+// changes of the debounced level, the first level
+// that settles is where it starts, not an edge
+pub struct EdgeDetector {
+    debouncer: Debouncer,
+    last: Option<PinLevel>,
+}
+
+impl EdgeDetector {
+    pub fn new(settle: Duration) -> Self {
+        EdgeDetector {
+            debouncer: Debouncer::new(settle),
+            last: None,
+        }
+    }
+
+    pub fn update(&mut self, raw: PinLevel, now: Instant) -> Option<Edge> {
+        let level = self.debouncer.update(raw, now)?;
+        match (self.last.replace(level)?, level) {
+            (PinLevel::Low, PinLevel::High) => Some(Edge::Rising),
+            (PinLevel::High, PinLevel::Low) => Some(Edge::Falling),
+            _ => None,
+        }
+    }
+}
+
+pub fn settle_for(appstate: &AppState, pin: i32) -> Duration {
+    let settle_ms = appstate
+        .debounce
+        .lock()
+        .unwrap()
+        .get(&pin)
+        .copied()
+        .unwrap_or(0);
+
+    Duration::from_millis(settle_ms as u64)
+}
+
+// blocks until the pin has settled at level, None
+// if the sequence got stopped while waiting
+pub async fn wait_for_level(
+    appstate: &AppState,
+    pin: i32,
+    level: PinLevel,
+    stop: &AtomicBool,
+) -> Result<Option<Duration>, GpioError> {
+    let settle = settle_for(appstate, pin);
+    let mut debouncer = Debouncer::new(settle);
+    let start = Instant::now();
+
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let raw = appstate.gpio.lock().unwrap().get_level(pin)?;
+        if debouncer.update(raw, Instant::now()) == Some(level) {
+            return Ok(Some(start.elapsed()));
+        }
+
+        // undebounced pins keep the old tight loop, just
+        // without starving the rest of the runtime
+        if settle.is_zero() {
+            tokio::task::yield_now().await;
+        } else {
+            sleep(SAMPLE_INTERVAL).await;
+        }
+    }
+}
+
+// how often the input watcher checks for newly debounced pins
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+// debounced input pins are sampled in the background, every
+// settled edge lands in the log and refreshes the pin panel,
+// so a bouncing button shows up as a single press
+pub fn watch_inputs(appstate: AppState) {
+    tokio::spawn(async move {
+        let mut detectors: HashMap<i32, (u32, EdgeDetector)> = HashMap::new();
+
+        loop {
+            let debounced: Vec<(i32, u32)> = appstate
+                .debounce
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(pin, settle_ms)| (*pin, *settle_ms))
+                .collect();
+
+            // a changed settle time starts over
+            detectors.retain(|pin, (settle_ms, _)| {
+                debounced.contains(&(*pin, *settle_ms))
+            });
+
+            let mut edges = Vec::new();
+            {
+                let gpio = appstate.gpio.lock().unwrap();
+                let now = Instant::now();
+
+                for (pin, settle_ms) in &debounced {
+                    if !gpio.initialized || !gpio.is_input(*pin) {
+                        detectors.remove(pin);
+                        continue;
+                    }
+                    let Ok(raw) = gpio.get_level(*pin) else {
+                        continue;
+                    };
+
+                    let (_, detector) =
+                        detectors.entry(*pin).or_insert_with(|| {
+                            let settle =
+                                Duration::from_millis(*settle_ms as u64);
+                            (*settle_ms, EdgeDetector::new(settle))
+                        });
+                    if let Some(edge) = detector.update(raw, now) {
+                        edges.push((*pin, edge));
+                    }
+                }
+            }
+
+            for (pin, edge) in &edges {
+                let _ = log_info(&appstate, format!("GPIO {pin} {edge} edge"));
+            }
+            if !edges.is_empty() {
+                let _ = appstate.log_tx.send(trigger_html(&["pins-changed"]));
+            }
+
+            sleep(match debounced.is_empty() {
+                true => IDLE_INTERVAL,
+                false => SAMPLE_INTERVAL,
+            })
+            .await;
+        }
+    });
+}
+
+fn save(appstate: &AppState) {
+    let mut configs: Vec<DebounceConfig> = appstate
+        .debounce
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(pin, settle_ms)| DebounceConfig {
+            pin: *pin,
+            settle_ms: *settle_ms,
+        })
+        .collect();
+    configs.sort_by_key(|config| config.pin);
+
+    if let Err(e) = save_debounce(&configs) {
+        let _ = log_error(appstate, format!("Failed to save config: {}", e));
+    }
+}
+
+// a settle time of 0 turns debouncing off
+pub async fn set_debounce(
+    State(appstate): State<AppState>,
+    Form(input): Form<DebounceConfig>,
+) -> Html<String> {
+    if let Err(e) = input.validate() {
+        return log_error(&appstate, e);
+    }
+
+    {
+        let mut debounce = appstate.debounce.lock().unwrap();
+        match input.settle_ms {
+            0 => debounce.remove(&input.pin),
+            settle_ms => debounce.insert(input.pin, settle_ms),
+        };
+    }
+    save(&appstate);
+
+    match input.settle_ms {
+        0 => log_info(&appstate, format!("GPIO {} not debounced", input.pin)),
+        settle_ms => log_info(
+            &appstate,
+            format!("GPIO {} debounced over {}ms", input.pin, settle_ms),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTLE: Duration = Duration::from_millis(5);
+
+    // one sample per ms from start, like the watcher takes them
+    fn feed(
+        detector: &mut EdgeDetector,
+        start: Instant,
+        offset_ms: u64,
+        samples: &[PinLevel],
+    ) -> Vec<(u64, Edge)> {
+        samples
+            .iter()
+            .enumerate()
+            .filter_map(|(i, raw)| {
+                let ms = offset_ms + i as u64;
+                let now = start + Duration::from_millis(ms);
+                detector.update(*raw, now).map(|edge| (ms, edge))
+            })
+            .collect()
+    }
+
+    fn steady(level: PinLevel, ms: usize) -> Vec<PinLevel> {
+        vec![level; ms]
+    }
+
+    #[test]
+    fn nothing_until_the_first_level_settles() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(SETTLE);
+
+        for ms in 0..5 {
+            let now = start + Duration::from_millis(ms);
+            assert_eq!(debouncer.update(PinLevel::Low, now), None);
+        }
+        let now = start + Duration::from_millis(5);
+        assert_eq!(debouncer.update(PinLevel::Low, now), Some(PinLevel::Low));
+    }
+
+    #[test]
+    fn zero_settle_passes_samples_through() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::ZERO);
+
+        assert_eq!(
+            debouncer.update(PinLevel::High, start),
+            Some(PinLevel::High)
+        );
+        assert_eq!(debouncer.update(PinLevel::Low, start), Some(PinLevel::Low));
+    }
+
+    #[test]
+    fn bouncing_press_is_one_rising_edge() {
+        use PinLevel::{High, Low};
+
+        let start = Instant::now();
+        let mut detector = EdgeDetector::new(SETTLE);
+
+        let mut samples = steady(Low, 10);
+        samples.extend([High, Low, High, High, Low, High, Low, Low, High]);
+        samples.extend(steady(High, 20));
+
+        let edges = feed(&mut detector, start, 0, &samples);
+        // high for good from 18ms, settled 5ms later
+        assert_eq!(edges, vec![(23, Edge::Rising)]);
+    }
+
+    #[test]
+    fn bouncing_release_is_one_falling_edge() {
+        use PinLevel::{High, Low};
+
+        let start = Instant::now();
+        let mut detector = EdgeDetector::new(SETTLE);
+
+        let mut samples = steady(High, 10);
+        samples.extend([Low, High, Low, High, Low]);
+        samples.extend(steady(Low, 20));
+
+        let edges = feed(&mut detector, start, 0, &samples);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].1, Edge::Falling);
+    }
+
+    #[test]
+    fn glitch_shorter_than_settle_is_ignored() {
+        use PinLevel::{High, Low};
+
+        let start = Instant::now();
+        let mut detector = EdgeDetector::new(SETTLE);
+
+        let mut samples = steady(Low, 10);
+        samples.extend(steady(High, 4));
+        samples.extend(steady(Low, 20));
+
+        assert!(feed(&mut detector, start, 0, &samples).is_empty());
+    }
+
+    #[test]
+    fn press_and_release_are_two_edges() {
+        use PinLevel::{High, Low};
+
+        let start = Instant::now();
+        let mut detector = EdgeDetector::new(SETTLE);
+
+        let mut samples = steady(Low, 10);
+        samples.extend([High, Low, High]);
+        samples.extend(steady(High, 30));
+        samples.extend([Low, High, Low]);
+        samples.extend(steady(Low, 30));
+
+        let edges: Vec<Edge> = feed(&mut detector, start, 0, &samples)
+            .into_iter()
+            .map(|(_, edge)| edge)
+            .collect();
+        assert_eq!(edges, vec![Edge::Rising, Edge::Falling]);
+    }
+}
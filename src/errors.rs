@@ -5,6 +5,15 @@ pub enum GpioError {
     InvalidPin(i32),
     Setup,
     NotInitialized,
+    Pwm(String),
+    Servo(String),
+    Clock(String),
+    I2c(String),
+    Spi(String),
+    Uart(String),
+    BitBang(String),
+    Sensor(String),
+    HardwareDetection,
     /*
     Direction(i32),
     Set(i32),
@@ -13,7 +22,6 @@ pub enum GpioError {
     Clear(i32),
     PullDown(i32),
     PullUp(i32),
-    HardwareDetection,
     InvalidDevice(i32),
     */
 }
@@ -31,6 +39,33 @@ impl fmt::Display for GpioError {
             }
             GpioError::NotInitialized => {
                 write!(f, "GPIO Not Initialized")
+            }
+            GpioError::Pwm(msg) => {
+                write!(f, "PWM: {}", msg)
+            }
+            GpioError::Servo(msg) => {
+                write!(f, "Servo: {}", msg)
+            }
+            GpioError::Clock(msg) => {
+                write!(f, "Clock: {}", msg)
+            }
+            GpioError::I2c(msg) => {
+                write!(f, "I2C: {}", msg)
+            }
+            GpioError::Spi(msg) => {
+                write!(f, "SPI: {}", msg)
+            }
+            GpioError::Uart(msg) => {
+                write!(f, "UART: {}", msg)
+            }
+            GpioError::BitBang(msg) => {
+                write!(f, "Bit-bang: {}", msg)
+            }
+            GpioError::Sensor(msg) => {
+                write!(f, "Sensor: {}", msg)
+            }
+            GpioError::HardwareDetection => {
+                write!(f, "Failed to Detect Raspberry Pi Peripheral Address")
             } /*
               GpioError::Direction(pin) => {
                   write!(f, "Failed to set direction {}", pin)
@@ -53,9 +88,6 @@ impl fmt::Display for GpioError {
               GpioError::PullUp(pin) => {
                   write!(f, "Failed to set {} to pull up", pin)
               }
-              GpioError::HardwareDetection => {
-                  write!(f, "Failed to Detect Raspberry Pi Peripheral Address")
-              }
               GpioError::InvalidDevice(dev) => {
                   write!(f, "Invalid device {}", dev)
               }
@@ -63,3 +95,36 @@ impl fmt::Display for GpioError {
         }
     }
 }
+
+#[derive(Debug)]
+pub enum ActionError {
+    UnknownAction(String),
+    InvalidPin(i32),
+    ReservedPin(i32),
+    NegativeDelay(i32),
+    InvalidArgument(String),
+}
+
+impl Error for ActionError {}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActionError::UnknownAction(action) => {
+                write!(f, "Not a valid action: {}", action)
+            }
+            ActionError::InvalidPin(pin) => {
+                write!(f, "GPIO {} is not on this board", pin)
+            }
+            ActionError::ReservedPin(pin) => {
+                write!(f, "GPIO {} is reserved for the HAT ID EEPROM", pin)
+            }
+            ActionError::NegativeDelay(time) => {
+                write!(f, "Delay can't be negative: {}", time)
+            }
+            ActionError::InvalidArgument(msg) => {
+                write!(f, "Invalid argument: {}", msg)
+            }
+        }
+    }
+}
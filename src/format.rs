@@ -0,0 +1,189 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{io, path::Path};
+use toml_edit::{ser::ValueSerializer, DocumentMut, Item, Table};
+
+// picked by file extension, anything unknown is json
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+
+        match ext.as_deref() {
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+
+    // everything goes through json values so the
+    // migrations only have one shape to deal with
+    pub fn parse(self, text: &str) -> io::Result<Value> {
+        match self {
+            Format::Json => serde_json::from_str(text).map_err(invalid),
+            Format::Toml => toml_edit::de::from_str(text).map_err(invalid),
+            Format::Yaml => serde_yaml::from_str(text).map_err(invalid),
+        }
+    }
+
+    // previous is what's on disk now, for toml its comments
+    // and layout are kept for every key that's still around.
+    // yaml comments don't survive, serde_yaml has no way to keep them
+    pub fn render(
+        self,
+        value: &Value,
+        previous: Option<&str>,
+    ) -> io::Result<String> {
+        match self {
+            Format::Json => {
+                serde_json::to_string_pretty(value).map_err(invalid)
+            }
+            Format::Yaml => serde_yaml::to_string(value).map_err(invalid),
+            Format::Toml => {
+                let value = without_nulls(value);
+                let Value::Object(map) = &value else {
+                    return Err(invalid("toml needs a table at the top"));
+                };
+
+                match previous.and_then(|text| text.parse::<DocumentMut>().ok())
+                {
+                    Some(mut doc) => {
+                        merge_table(doc.as_table_mut(), map)?;
+                        Ok(doc.to_string())
+                    }
+                    None => toml_edit::ser::to_string_pretty(&value)
+                        .map_err(invalid),
+                }
+            }
+        }
+    }
+}
+
+// toml has no null, a missing key reads back as None anyway
+fn without_nulls(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(items) => {
+            Value::Array(items.iter().map(without_nulls).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+fn to_toml(value: &Value) -> io::Result<toml_edit::Value> {
+    value.serialize(ValueSerializer::new()).map_err(invalid)
+}
+
+// writes new values into the parsed document, keeping
+// comments on the keys and [tables] that already exist
+fn merge_table(table: &mut Table, map: &Map<String, Value>) -> io::Result<()> {
+    let stale: Vec<String> = table
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !map.contains_key(key))
+        .collect();
+    for key in stale {
+        table.remove(&key);
+    }
+
+    for (key, value) in map {
+        match (table.get_mut(key), value) {
+            (Some(Item::Table(inner)), Value::Object(map)) => {
+                merge_table(inner, map)?;
+            }
+            (Some(Item::Value(old)), value) => {
+                let decor = old.decor().clone();
+                *old = to_toml(value)?;
+                *old.decor_mut() = decor;
+            }
+            // new sections get a [table] of their own
+            (_, Value::Object(_)) => {
+                let doc =
+                    toml_edit::ser::to_document(value).map_err(invalid)?;
+                table.insert(key, Item::Table(doc.as_table().clone()));
+            }
+            // new keys, or an array of tables, just get written out fresh
+            _ => {
+                table.insert(key, Item::Value(to_toml(value)?));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const COMMENTED: &str = r#"# pipin settings
+version = 2
+
+# pins that start as outputs
+[gpio]
+# the status led
+led = 17 # active high
+button = 27
+
+[server]
+port = 3000 # behind the proxy
+"#;
+
+    #[test]
+    fn toml_merge_keeps_comments_and_untouched_keys() {
+        let mut value = Format::Toml.parse(COMMENTED).unwrap();
+        value["server"]["port"] = json!(4000);
+        // nulls have no toml form, the key is dropped instead
+        value["gpio"]["button"] = Value::Null;
+
+        let text = Format::Toml.render(&value, Some(COMMENTED)).unwrap();
+
+        for comment in [
+            "# pipin settings",
+            "# pins that start as outputs",
+            "# the status led",
+            "# active high",
+            "# behind the proxy",
+        ] {
+            assert!(text.contains(comment), "lost {comment:?} in:\n{text}");
+        }
+        assert!(text.contains("port = 4000"), "{text}");
+        assert!(!text.contains("button"), "{text}");
+
+        let expected = json!({
+            "version": 2,
+            "gpio": { "led": 17 },
+            "server": { "port": 4000 },
+        });
+        assert_eq!(Format::Toml.parse(&text).unwrap(), expected);
+
+        // arrays keep their nulls, only keys go
+        let nested = json!({
+            "a": null,
+            "b": { "c": null, "d": 1 },
+            "e": [null, { "f": null, "g": false }],
+        });
+        assert_eq!(
+            without_nulls(&nested),
+            json!({ "b": { "d": 1 }, "e": [null, { "g": false }] }),
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use crate::{
+    errors::GpioError,
+    gpio::{Gpio, PinDirection, PinFunction},
+    logger::{log_error, log_info},
+    peripheral::{
+        detect_peripheral_base, oscillator_hz, stop_clock, Register,
+        RegisterBlock, CLOCK_MANAGER_OFFSET, CM_ENAB, CM_PASSWORD,
+    },
+    AppState,
+};
+
+use axum::{extract::State, response::Html, Form};
+use serde::{Deserialize, Serialize};
+
+// CM_GPnCTL and CM_GPnDIV
+const CM_GP0CTL: Register = Register::at::<28>();
+const CM_GP0DIV: Register = Register::at::<29>();
+const CM_GP1CTL: Register = Register::at::<30>();
+const CM_GP1DIV: Register = Register::at::<31>();
+const CM_GP2CTL: Register = Register::at::<32>();
+const CM_GP2DIV: Register = Register::at::<33>();
+
+const MAX_DIVI: u32 = 4095;
+const MAX_DIVF: u32 = 4095;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClockSource {
+    Oscillator = 1,
+    TestDebug0 = 2,
+    TestDebug1 = 3,
+    Plla = 4,
+    Pllc = 5,
+    Plld = 6,
+    Hdmi = 7,
+}
+
+impl ClockSource {
+    // only the ones that don't move around
+    // with the firmware's clock settings
+    fn hz(self, peripheral_base: u64) -> Option<u32> {
+        match self {
+            ClockSource::Oscillator => Some(oscillator_hz(peripheral_base)),
+            ClockSource::Plld => match oscillator_hz(peripheral_base) {
+                54_000_000 => Some(750_000_000),
+                _ => Some(500_000_000),
+            },
+            _ => None,
+        }
+    }
+}
+
+// general purpose clocks and the
+// gpio that each one comes out on
+fn clock_pin(clock: u8) -> Result<(Register, Register, i32), GpioError> {
+    match clock {
+        0 => Ok((CM_GP0CTL, CM_GP0DIV, 4)),
+        1 => Ok((CM_GP1CTL, CM_GP1DIV, 5)),
+        2 => Ok((CM_GP2CTL, CM_GP2DIV, 6)),
+        _ => Err(GpioError::Clock(format!("no GPCLK{clock}"))),
+    }
+}
+
+// mash smooths the fractional divider but
+// needs headroom in the integer part
+fn min_divi(mash: u8) -> u32 {
+    match mash {
+        0 => 1,
+        1 => 2,
+        2 => 3,
+        _ => 5,
+    }
+}
+
+struct Registers {
+    clock: RegisterBlock,
+    base: u64,
+}
+
+// GPCLK0-2 out of the clock manager
+// same as hw pwm, this needs root
+pub struct GpClock {
+    regs: Option<Registers>,
+}
+
+impl GpClock {
+    pub fn new() -> Self {
+        GpClock { regs: None }
+    }
+
+    fn registers(&mut self) -> Result<&Registers, GpioError> {
+        if self.regs.is_none() {
+            let base = detect_peripheral_base()?;
+            self.regs = Some(Registers {
+                clock: RegisterBlock::map(base + CLOCK_MANAGER_OFFSET)?,
+                base,
+            });
+        }
+
+        self.regs.as_ref().ok_or(GpioError::NotInitialized)
+    }
+
+    // returns the output frequency if we know the source's
+    pub fn enable(
+        &mut self,
+        gpio: &mut Gpio,
+        clock: u8,
+        source: ClockSource,
+        divi: u32,
+        divf: u32,
+        mash: u8,
+    ) -> Result<Option<f64>, GpioError> {
+        if !gpio.initialized {
+            return Err(GpioError::NotInitialized);
+        }
+
+        let (ctl, div, pin) = clock_pin(clock)?;
+
+        if mash > 3 {
+            return Err(GpioError::Clock("mash must be 0-3".to_string()));
+        }
+
+        if !(min_divi(mash)..=MAX_DIVI).contains(&divi) || divf > MAX_DIVF {
+            return Err(GpioError::Clock(format!(
+                "divi must be {}-{MAX_DIVI} for mash {mash}, divf 0-{MAX_DIVF}",
+                min_divi(mash)
+            )));
+        }
+
+        let regs = self.registers()?;
+        stop_clock(&regs.clock, ctl)?;
+
+        regs.clock.write(div, CM_PASSWORD | (divi << 12) | divf);
+        regs.clock
+            .write(ctl, CM_PASSWORD | ((mash as u32) << 9) | source as u32);
+        regs.clock.write(
+            ctl,
+            CM_PASSWORD | ((mash as u32) << 9) | CM_ENAB | source as u32,
+        );
+
+        let freq = source
+            .hz(regs.base)
+            .map(|hz| hz as f64 / (divi as f64 + divf as f64 / 4096.0));
+
+        gpio.set_function(pin, PinFunction::Alt0)?;
+        Ok(freq)
+    }
+
+    // stops the clock and leaves its pin as an input
+    pub fn disable(
+        &mut self,
+        gpio: &mut Gpio,
+        clock: u8,
+    ) -> Result<(), GpioError> {
+        let (ctl, _, pin) = clock_pin(clock)?;
+
+        if let Some(regs) = &self.regs {
+            stop_clock(&regs.clock, ctl)?;
+        }
+
+        gpio.set_direction(pin, PinDirection::Input)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GpClockForm {
+    pub clock: u8,
+    pub source: ClockSource,
+    pub divi: u32,
+    #[serde(default)]
+    pub divf: u32,
+    #[serde(default)]
+    pub mash: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GpClockStopForm {
+    pub clock: u8,
+}
+
+pub async fn enable_gpclk(
+    State(appstate): State<AppState>,
+    Form(input): Form<GpClockForm>,
+) -> Html<String> {
+    let mut gpclk = appstate.gpclk.lock().unwrap();
+    let mut gpio = appstate.gpio.lock().unwrap();
+
+    match gpclk.enable(
+        &mut gpio,
+        input.clock,
+        input.source,
+        input.divi,
+        input.divf,
+        input.mash,
+    ) {
+        Ok(Some(freq)) => log_info(
+            &appstate,
+            format!("GPCLK{} running at {:.0}Hz", input.clock, freq),
+        ),
+        Ok(None) => log_info(
+            &appstate,
+            format!("GPCLK{} running from {:?}", input.clock, input.source),
+        ),
+        Err(e) => log_error(
+            &appstate,
+            format!("failed to enable GPCLK{}: {e}", input.clock),
+        ),
+    }
+}
+
+pub async fn disable_gpclk(
+    State(appstate): State<AppState>,
+    Form(input): Form<GpClockStopForm>,
+) -> Html<String> {
+    let mut gpclk = appstate.gpclk.lock().unwrap();
+    let mut gpio = appstate.gpio.lock().unwrap();
+
+    match gpclk.disable(&mut gpio, input.clock) {
+        Ok(_) => log_info(&appstate, format!("GPCLK{} stopped", input.clock)),
+        Err(e) => log_error(
+            &appstate,
+            format!("failed to stop GPCLK{}: {e}", input.clock),
+        ),
+    }
+}
@@ -1,13 +1,19 @@
-use crate::errors::GpioError;
+use crate::{
+    errors::GpioError, logger::escape_html, names::name_of,
+    peripheral::detect_peripheral_base,
+};
 use nix::{
-    libc::O_SYNC,
+    libc::{off_t, O_SYNC},
     sys::mman::{mmap, munmap, MapFlags, ProtFlags},
 };
+use serde::{Deserialize, Serialize};
 use std::{
+    fmt,
     fs::OpenOptions,
     num::NonZero,
     os::unix::fs::OpenOptionsExt,
     ptr::{read_volatile, write_volatile, NonNull},
+    str::FromStr,
     sync::atomic::{AtomicPtr, Ordering},
     thread::sleep,
     time::Duration,
@@ -20,6 +26,9 @@ const GPIO_LEV_OFFSET: usize = 13;
 const GPIO_PULL_OFFSET: usize = 37;
 const GPIO_PULLCLK0_OFFSET: usize = 38;
 
+// from the peripheral base, only needed for /dev/mem
+const GPIO_BLOCK_OFFSET: u64 = 0x20_0000;
+
 // https://pinout.xyz/
 #[derive(Copy, Clone, Debug)]
 pub enum PinType {
@@ -34,7 +43,7 @@ pub enum PinType {
     Pcm,  // yellow
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum PullType {
     None = 0,
     Down = 1,
@@ -47,12 +56,95 @@ pub enum PinDirection {
     Output,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PinLevel {
     High,
     Low,
 }
 
+impl PinLevel {
+    pub fn inverted(self) -> Self {
+        match self {
+            PinLevel::High => PinLevel::Low,
+            PinLevel::Low => PinLevel::High,
+        }
+    }
+}
+
+impl FromStr for PinLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "high" | "1" => Ok(PinLevel::High),
+            "low" | "0" => Ok(PinLevel::Low),
+            other => Err(format!("Invalid level `{}`", escape_html(other))),
+        }
+    }
+}
+
+impl fmt::Display for PinLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinLevel::High => write!(f, "HIGH"),
+            PinLevel::Low => write!(f, "LOW"),
+        }
+    }
+}
+
+// GPFSEL values, the alt numbering isn't in order
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PinFunction {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+// how the gpio registers get mapped
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    // just the gpio block, works for the gpio group
+    GpioMem,
+    // the whole bus at the detected base, needs root
+    Mem,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "gpiomem" => Ok(Backend::GpioMem),
+            "mem" => Ok(Backend::Mem),
+            other => Err(format!(
+                "Invalid backend `{other}`, expected gpiomem or mem"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::GpioMem => write!(f, "/dev/gpiomem"),
+            Backend::Mem => write!(f, "/dev/mem"),
+        }
+    }
+}
+
+// what's currently generating a waveform on a pin
+#[derive(Copy, Clone, Debug)]
+pub struct PinPwm {
+    pub freq: u32,
+    pub duty: f32,
+    pub hardware: bool,
+}
+
 #[derive(Clone, Debug)]
 pub enum PinColumn {
     Left,
@@ -69,23 +161,30 @@ pub struct Pin {
 
     //physical state
     pull: PullType,
+    // logical, see inverted
     level: PinLevel,
     direction: PinDirection,
+    pwm: Option<PinPwm>,
+
+    // active low, logical HIGH drives the pin LOW
+    inverted: bool,
 }
 
 pub struct Gpio {
     pub initialized: bool,
     pub pins: Vec<Pin>,
 
+    backend: Backend,
     gpio_map: Option<AtomicPtr<u32>>,
 }
 
 impl Gpio {
-    pub fn new() -> Self {
+    pub fn new(backend: Backend) -> Self {
         Gpio {
             gpio_map: None,
             initialized: false,
             pins: default_pins(),
+            backend,
         }
     }
 
@@ -101,6 +200,71 @@ impl Gpio {
         Ok(pin)
     }
 
+    // looks up a gpio number in the header layout
+    // None if this board doesn't expose it
+    pub fn pin_type(&self, pin: i32) -> Option<PinType> {
+        self.pins
+            .iter()
+            .find(|p| p.number == Some(pin))
+            .map(|p| p.pin_type)
+    }
+
+    pub fn pwm(&self, pin: i32) -> Option<PinPwm> {
+        self.pins
+            .iter()
+            .find(|p| p.number == Some(pin))
+            .and_then(|p| p.pwm)
+    }
+
+    pub fn is_input(&self, pin: i32) -> bool {
+        self.pins.iter().any(|p| {
+            p.number == Some(pin) && matches!(p.direction, PinDirection::Input)
+        })
+    }
+
+    pub fn is_inverted(&self, pin: i32) -> bool {
+        self.pins
+            .iter()
+            .any(|p| p.number == Some(pin) && p.inverted)
+    }
+
+    // outputs get driven again so they keep their logical
+    // level, before setup there's nothing to drive. soft pwm
+    // picks the new polarity up on its next edge, hardware
+    // pwm on its next configure
+    pub fn set_inverted(
+        &mut self,
+        pin: i32,
+        inverted: bool,
+    ) -> Result<(), GpioError> {
+        let mut redrive = None;
+        for p in &mut self.pins {
+            if p.number == Some(pin) {
+                p.inverted = inverted;
+                if matches!(p.direction, PinDirection::Output)
+                    && p.pwm.is_none()
+                {
+                    redrive = Some(p.level);
+                }
+                break;
+            }
+        }
+
+        match redrive {
+            Some(level) if self.initialized => self.set_level(pin, level),
+            _ => Ok(()),
+        }
+    }
+
+    // logical <-> physical, same both ways
+    fn physical(&self, pin: i32, level: PinLevel) -> PinLevel {
+        if self.is_inverted(pin) {
+            level.inverted()
+        } else {
+            level
+        }
+    }
+
     // https://stackoverflow.com/a/44510388/17123405
     // helper func to read a volatile register
     unsafe fn read_register(&self, offset: usize) -> Result<u32, GpioError> {
@@ -167,30 +331,34 @@ impl Gpio {
                 }
             };
 
-            // 0 since we're using
-            // /dev/gpiomem
-            // if we want manual control,
-            // we'll have to
-            // use /dev/mem and
-            // detect_peripheral_address()
-            // but requires sudo
+            // 0 since /dev/gpiomem is
+            // only the gpio block
+            // /dev/mem needs the
+            // detect_peripheral_base()
+            // and sudo
             // rather than gpio group
             // `groups`
             // `sudo usermod -a -G gpio <user>`
-            let gpio_address = 0;
+            let (path, gpio_address) = match self.backend {
+                Backend::GpioMem => ("/dev/gpiomem", 0),
+                Backend::Mem => (
+                    "/dev/mem",
+                    (detect_peripheral_base()? + GPIO_BLOCK_OFFSET) as off_t,
+                ),
+            };
 
             let dev_mem = match OpenOptions::new()
                 .read(true)
                 .write(true)
                 .custom_flags(O_SYNC)
-                .open("/dev/gpiomem")
+                .open(path)
             {
                 Ok(dev_mem) => {
-                    println!("Opened /dev/gpiomem");
+                    println!("Opened {path}");
                     dev_mem
                 }
                 Err(e) => {
-                    println!("Failed to open /dev/gpiomem: {e}");
+                    println!("Failed to open {path}: {e}");
                     return Err(GpioError::Setup);
                 }
             };
@@ -247,8 +415,8 @@ impl Gpio {
             return Err(GpioError::NotInitialized);
         }
 
-        // should we reset all pins before terminating?
-        // self.reset()?;
+        // pin states on the way out are up to the
+        // shutdown policy, see shutdown::safe_state
 
         if let Some(atomic_ptr) = &self.gpio_map {
             unsafe {
@@ -293,6 +461,36 @@ impl Gpio {
     ) -> Result<(), GpioError> {
         self.validate_input(pin)?;
 
+        match direction {
+            PinDirection::Output => {
+                self.set_function(pin, PinFunction::Output)?
+            }
+            PinDirection::Input => {
+                self.set_function(pin, PinFunction::Input)?
+            }
+        }
+
+        for p in &mut self.pins {
+            if let Some(num) = p.number {
+                if num == pin {
+                    p.direction = direction;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // writes the 3 GPFSEL bits for a pin
+    // set_direction() is the one to use for plain in/out
+    pub fn set_function(
+        &mut self,
+        pin: i32,
+        function: PinFunction,
+    ) -> Result<(), GpioError> {
+        self.validate_input(pin)?;
+
         unsafe {
             let reg = (pin / 10) as usize;
             let bit = ((pin % 10) * 3) as usize;
@@ -303,30 +501,13 @@ impl Gpio {
             // clear
             reg_value &= !(7 << bit);
 
-            // then set bits based on dir
-            match direction {
-                PinDirection::Output => {
-                    reg_value |= 1 << bit;
-                }
-                PinDirection::Input => {
-                    // should do nothing here
-                    // since clearing it
-                }
-            }
+            // then set the function bits
+            reg_value |= (function as u32) << bit;
 
             // wriet it back
             self.write_register(reg, reg_value)?;
         }
 
-        for p in &mut self.pins {
-            if let Some(num) = p.number {
-                if num == pin {
-                    p.direction = direction;
-                    break;
-                }
-            }
-        }
-
         Ok(())
     }
 
@@ -343,7 +524,7 @@ impl Gpio {
         self.set_direction(pin, PinDirection::Output)?;
 
         unsafe {
-            match level {
+            match self.physical(pin, level) {
                 PinLevel::High => {
                     println!("Setting Pin Level to High");
                     self.write_register(GPIO_SET_OFFSET, 1 << pin)?;
@@ -355,20 +536,7 @@ impl Gpio {
             }
         }
 
-        // pins aren't mapped 1 to 1 on
-        // physical pins to the vector index
-        // so using something like pins[pin]
-        // fails and sets the wrong
-        // pin idx
-        // todo: better way to handle this
-        for p in &mut self.pins {
-            if let Some(num) = p.number {
-                if num == pin {
-                    p.level = level;
-                    break;
-                }
-            }
-        }
+        self.record_level(pin, level);
 
         Ok(())
     }
@@ -420,7 +588,67 @@ impl Gpio {
         Ok(())
     }
 
+    // every active low pin as a bit mask
+    pub fn inverted_mask(&self) -> u32 {
+        self.pins
+            .iter()
+            .filter(|p| p.inverted)
+            .filter_map(|p| p.number)
+            .fold(0, |mask, pin| mask | 1 << pin)
+    }
+
+    // logical levels for every pin in the masks, active low
+    // pins trade places between the two before they're written.
+    // same caveats as write_physical_levels
+    pub fn write_levels(
+        &self,
+        high_mask: u32,
+        low_mask: u32,
+    ) -> Result<(), GpioError> {
+        let inverted = self.inverted_mask();
+        self.write_physical_levels(
+            (high_mask & !inverted) | (low_mask & inverted),
+            (low_mask & !inverted) | (high_mask & inverted),
+        )
+    }
+
+    // writes GPSET0/GPCLR0 directly for every pin in the masks,
+    // the voltage on the wire whatever the pin's polarity.
+    // skips direction setup and the pin state bookkeeping
+    // so callers need to set the pins to output first
+    pub fn write_physical_levels(
+        &self,
+        set_mask: u32,
+        clr_mask: u32,
+    ) -> Result<(), GpioError> {
+        unsafe {
+            if set_mask != 0 {
+                self.write_register(GPIO_SET_OFFSET, set_mask)?;
+            }
+            if clr_mask != 0 {
+                self.write_register(GPIO_CLR_OFFSET, clr_mask)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_pwm_state(&mut self, pin: i32, pwm: Option<PinPwm>) {
+        for p in &mut self.pins {
+            if p.number == Some(pin) {
+                p.pwm = pwm;
+                break;
+            }
+        }
+    }
+
     pub fn get_level(&self, pin: i32) -> Result<PinLevel, GpioError> {
+        Ok(self.physical(pin, self.get_physical_level(pin)?))
+    }
+
+    // what's on the wire, for protocols like the dht's
+    // that mean a voltage rather than on or off
+    pub fn get_physical_level(&self, pin: i32) -> Result<PinLevel, GpioError> {
         self.validate_input(pin)?;
 
         unsafe {
@@ -433,6 +661,35 @@ impl Gpio {
         }
     }
 
+    // the pin state bookkeeping only, for callers that
+    // wrote the level themselves through write_levels
+    pub fn record_level(&mut self, pin: i32, level: PinLevel) {
+        // pins aren't mapped 1 to 1 on
+        // physical pins to the vector index
+        // so using something like pins[pin]
+        // fails and sets the wrong
+        // pin idx
+        // todo: better way to handle this
+        for p in &mut self.pins {
+            if let Some(num) = p.number {
+                if num == pin {
+                    p.level = level;
+                    break;
+                }
+            }
+        }
+    }
+
+    // set_level by voltage, the bookkeeping stays logical
+    pub fn set_physical_level(
+        &mut self,
+        pin: i32,
+        level: PinLevel,
+    ) -> Result<(), GpioError> {
+        let level = self.physical(pin, level);
+        self.set_level(pin, level)
+    }
+
     // do we realistically need this?
     // assuming in the future we need to determine direction
     // at a quick glance for the frontend
@@ -496,6 +753,41 @@ impl Gpio {
             let pin_num = pin.number.unwrap();
             let unique_id = format!("gpio-pin-{}", pin_num);
 
+            let label = match name_of(pin_num) {
+                Some(name) => format!("{} ({})", name, pin.label),
+                None => pin.label.clone(),
+            };
+
+            // pwm'd pins get their settings tacked on,
+            // toggling one stops its pwm first
+            let (label, pwm_class) = match pin.pwm {
+                Some(pwm) => (
+                    format!(
+                        "{} {} {}Hz {}%",
+                        label,
+                        if pwm.hardware { "HW PWM" } else { "PWM" },
+                        pwm.freq,
+                        pwm.duty
+                    ),
+                    "pwm",
+                ),
+                None => (label, ""),
+            };
+
+            // level and the checkbox stay logical,
+            // show what's actually on the pin next to it
+            let (label, inverted_class) = match pin.inverted {
+                true => (
+                    format!(
+                        "{} (active low, pin {})",
+                        label,
+                        pin.level.inverted()
+                    ),
+                    "inverted",
+                ),
+                false => (label, ""),
+            };
+
             let ret = format!(
                 r#"
             <div id="{0}" class="pin-wrapper">
@@ -503,11 +795,18 @@ impl Gpio {
             <input type="checkbox" {5} id="checkbox-{1}" 
                 class="pin-checkbox" ws-send
                 hx-trigger="change" hx-vals='{{"pin": "{1}"}}'>
-            <span class="pin {2} {3}">{4}</span>
+            <span class="pin {2} {3} {6} {7}">{4}</span>
             </label>
             </div>
          "#,
-                unique_id, pin_num, powered, level, pin.label, checked
+                unique_id,
+                pin_num,
+                powered,
+                level,
+                label,
+                checked,
+                pwm_class,
+                inverted_class
             );
             Ok(ret)
         } else {
@@ -566,6 +865,8 @@ pub fn default_pins() -> Vec<Pin> {
         level: PinLevel::Low,
         direction: PinDirection::Input,
         pull: PullType::None,
+        pwm: None,
+        inverted: false,
     };
 
     pins.push(pin(None, PinColumn::Left, PinType::Power3v3, "3v3 Power"));
@@ -0,0 +1,63 @@
+use crate::logger::escape_html;
+
+// helpers for the byte oriented buses
+// "0x3c", "3c" and "60" all come in from forms.
+// errors echo the input and end up in the log panel
+
+// 0x prefix means hex, anything else is decimal
+pub fn parse_number(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    };
+
+    parsed.map_err(|_| format!("Invalid number `{}`", escape_html(s)))
+}
+
+pub fn parse_u8(s: &str) -> Result<u8, String> {
+    let value = parse_number(s)?;
+    u8::try_from(value).map_err(|_| {
+        format!("`{}` doesn't fit in a byte", escape_html(s.trim()))
+    })
+}
+
+// "de ad be ef", "0xde,0xad" or "deadbeef"
+// bytes are always hex here
+pub fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    for token in s.split(|c: char| c.is_whitespace() || c == ',') {
+        let token = token.trim_start_matches("0x").trim_start_matches("0X");
+        if token.is_empty() {
+            continue;
+        }
+
+        if token.len() % 2 != 0 {
+            return Err(format!(
+                "Odd number of hex digits in `{}`",
+                escape_html(token)
+            ));
+        }
+
+        for i in (0..token.len()).step_by(2) {
+            let byte = token
+                .get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| {
+                    format!("Invalid hex `{}`", escape_html(token))
+                })?;
+            bytes.push(byte);
+        }
+    }
+
+    Ok(bytes)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,405 @@
+use crate::{
+    errors::GpioError,
+    gpio::{Gpio, PinFunction, PinLevel, PinPwm},
+    logger::{log_error, log_info},
+    names::deserialize_pin,
+    peripheral::{
+        detect_peripheral_base, oscillator_hz, stop_clock, Register,
+        RegisterBlock, CLOCK_MANAGER_OFFSET, CM_ENAB, CM_PASSWORD, PWM_OFFSET,
+    },
+    pwm::pins_changed,
+    AppState,
+};
+
+use axum::{extract::State, response::Response, Form};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+// register offsets in words
+const PWM_CTL: Register = Register::at::<0>();
+const PWM_RNG1: Register = Register::at::<4>();
+const PWM_DAT1: Register = Register::at::<5>();
+const PWM_RNG2: Register = Register::at::<8>();
+const PWM_DAT2: Register = Register::at::<9>();
+
+const CM_PWMCTL: Register = Register::at::<40>();
+const CM_PWMDIV: Register = Register::at::<41>();
+
+// per channel CTL bits, channel 2 is shifted up by 8
+const PWM_PWEN: u32 = 1 << 0;
+// inverts the output, for active low pins
+const PWM_POLA: u32 = 1 << 4;
+const PWM_MSEN: u32 = 1 << 7;
+
+const CM_SRC_OSC: u32 = 1;
+
+// 12 bit integer divisor, anything under 2 stops the clock
+const MIN_DIVISOR: u32 = 2;
+const MAX_DIVISOR: u32 = 4095;
+const DEFAULT_DIVISOR: u32 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PwmChannel {
+    Pwm0,
+    Pwm1,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PwmMode {
+    // fixed period of range ticks, high for data ticks
+    MarkSpace,
+    // spreads the high ticks evenly over the range
+    Balanced,
+}
+
+impl PwmChannel {
+    fn shift(self) -> u32 {
+        match self {
+            PwmChannel::Pwm0 => 0,
+            PwmChannel::Pwm1 => 8,
+        }
+    }
+
+    fn range_register(self) -> Register {
+        match self {
+            PwmChannel::Pwm0 => PWM_RNG1,
+            PwmChannel::Pwm1 => PWM_RNG2,
+        }
+    }
+
+    fn data_register(self) -> Register {
+        match self {
+            PwmChannel::Pwm0 => PWM_DAT1,
+            PwmChannel::Pwm1 => PWM_DAT2,
+        }
+    }
+}
+
+// 12 and 18 share PWM0, 13 and 19 share PWM1
+pub fn pwm_pin(pin: i32) -> Option<(PwmChannel, PinFunction)> {
+    match pin {
+        12 => Some((PwmChannel::Pwm0, PinFunction::Alt0)),
+        13 => Some((PwmChannel::Pwm1, PinFunction::Alt0)),
+        18 => Some((PwmChannel::Pwm0, PinFunction::Alt5)),
+        19 => Some((PwmChannel::Pwm1, PinFunction::Alt5)),
+        _ => None,
+    }
+}
+
+struct Registers {
+    pwm: RegisterBlock,
+    clock: RegisterBlock,
+    oscillator_hz: u32,
+}
+
+// drives the BCM PWM block and its clock
+// needs /dev/mem so pipin has to run as root
+pub struct HwPwm {
+    regs: Option<Registers>,
+    divisor: u32,
+    // a failed map, so callers with a fallback don't
+    // go back to /dev/mem on every step of a servo move
+    unmappable: bool,
+    // past the safe states nothing gets started again
+    shutting_down: watch::Receiver<bool>,
+}
+
+impl HwPwm {
+    pub fn new(shutting_down: watch::Receiver<bool>) -> Self {
+        HwPwm {
+            regs: None,
+            divisor: DEFAULT_DIVISOR,
+            unmappable: false,
+            shutting_down,
+        }
+    }
+
+    // only the first call tries the mapping, the hw pwm
+    // forms still try again and report why it failed
+    pub fn available(&mut self) -> bool {
+        if !self.unmappable && self.registers().is_err() {
+            self.unmappable = true;
+        }
+        !self.unmappable
+    }
+
+    // mapped on first use since most people
+    // won't have root
+    fn registers(&mut self) -> Result<&Registers, GpioError> {
+        if self.regs.is_none() {
+            let base = detect_peripheral_base()?;
+            let regs = Registers {
+                pwm: RegisterBlock::map(base + PWM_OFFSET)?,
+                clock: RegisterBlock::map(base + CLOCK_MANAGER_OFFSET)?,
+                oscillator_hz: oscillator_hz(base),
+            };
+
+            println!("Mapped pwm registers at {base:#x}");
+            self.regs = Some(regs);
+            self.unmappable = false;
+            self.write_clock(self.divisor)?;
+        }
+
+        self.regs.as_ref().ok_or(GpioError::NotInitialized)
+    }
+
+    // both channels run off the same clock
+    // so this changes the frequency of both
+    pub fn set_divisor(&mut self, divisor: u32) -> Result<(), GpioError> {
+        if !(MIN_DIVISOR..=MAX_DIVISOR).contains(&divisor) {
+            return Err(GpioError::Pwm(format!(
+                "divisor must be between {MIN_DIVISOR} and {MAX_DIVISOR}"
+            )));
+        }
+
+        self.registers()?;
+        self.write_clock(divisor)?;
+        self.divisor = divisor;
+        Ok(())
+    }
+
+    fn write_clock(&self, divisor: u32) -> Result<(), GpioError> {
+        let regs = self.regs.as_ref().ok_or(GpioError::NotInitialized)?;
+
+        // pwm has to be off while the clock changes
+        let ctl = regs.pwm.read(PWM_CTL);
+        regs.pwm.write(PWM_CTL, 0);
+
+        stop_clock(&regs.clock, CM_PWMCTL)?;
+
+        regs.clock.write(CM_PWMDIV, CM_PASSWORD | (divisor << 12));
+        regs.clock
+            .write(CM_PWMCTL, CM_PASSWORD | CM_SRC_OSC | CM_ENAB);
+
+        regs.pwm.write(PWM_CTL, ctl);
+        Ok(())
+    }
+
+    pub fn clock_hz(&mut self) -> Result<u32, GpioError> {
+        let divisor = self.divisor;
+        Ok(self.registers()?.oscillator_hz / divisor)
+    }
+
+    // raw access to a channel's registers
+    pub fn configure(
+        &mut self,
+        gpio: &mut Gpio,
+        pin: i32,
+        mode: PwmMode,
+        range: u32,
+        data: u32,
+    ) -> Result<(), GpioError> {
+        if !gpio.initialized {
+            return Err(GpioError::NotInitialized);
+        }
+        if *self.shutting_down.borrow() {
+            return Err(GpioError::Pwm("shutting down".to_string()));
+        }
+
+        let (channel, function) = pwm_pin(pin).ok_or_else(|| {
+            GpioError::Pwm(format!("GPIO {pin} has no hardware pwm"))
+        })?;
+
+        if range == 0 || data > range {
+            return Err(GpioError::Pwm(
+                "range must be non zero and data at most range".to_string(),
+            ));
+        }
+
+        let clock_hz = self.clock_hz()?;
+        let regs = self.registers()?;
+        let shift = channel.shift();
+
+        // disable the channel while we change it
+        let mut ctl = regs.pwm.read(PWM_CTL) & !(0xFF << shift);
+        regs.pwm.write(PWM_CTL, ctl);
+
+        regs.pwm.write(channel.range_register(), range);
+        regs.pwm.write(channel.data_register(), data);
+
+        if mode == PwmMode::MarkSpace {
+            ctl |= PWM_MSEN << shift;
+        }
+        // duty stays the time the pin is on
+        if gpio.is_inverted(pin) {
+            ctl |= PWM_POLA << shift;
+        }
+        ctl |= PWM_PWEN << shift;
+        regs.pwm.write(PWM_CTL, ctl);
+
+        gpio.set_function(pin, function)?;
+        gpio.set_pwm_state(
+            pin,
+            Some(PinPwm {
+                freq: clock_hz / range,
+                duty: data as f32 / range as f32 * 100.0,
+                hardware: true,
+            }),
+        );
+
+        Ok(())
+    }
+
+    // mark-space at the current clock
+    pub fn set(
+        &mut self,
+        gpio: &mut Gpio,
+        pin: i32,
+        freq: u32,
+        duty: f32,
+    ) -> Result<(), GpioError> {
+        if !(0.0..=100.0).contains(&duty) {
+            return Err(GpioError::Pwm(
+                "duty must be between 0 and 100%".to_string(),
+            ));
+        }
+
+        let clock_hz = self.clock_hz()?;
+        if freq == 0 || clock_hz / freq < 2 {
+            return Err(GpioError::Pwm(format!(
+                "frequency must be between 1 and {}Hz",
+                clock_hz / 2
+            )));
+        }
+
+        let range = clock_hz / freq;
+        let data = (range as f64 * duty as f64 / 100.0).round() as u32;
+        self.configure(gpio, pin, PwmMode::MarkSpace, range, data)
+    }
+
+    // turns the channel off and hands the pin back as an output
+    // that's logically low, so an active low load is off too
+    pub fn stop(&mut self, gpio: &mut Gpio, pin: i32) -> Result<(), GpioError> {
+        let (channel, _) = pwm_pin(pin).ok_or_else(|| {
+            GpioError::Pwm(format!("GPIO {pin} has no hardware pwm"))
+        })?;
+
+        if let Some(regs) = &self.regs {
+            let ctl = regs.pwm.read(PWM_CTL) & !(PWM_PWEN << channel.shift());
+            regs.pwm.write(PWM_CTL, ctl);
+        }
+
+        gpio.set_pwm_state(pin, None);
+        gpio.set_level(pin, PinLevel::Low)
+    }
+
+    // only touches the pwm block, the gpio
+    // gets reset separately
+    pub fn stop_all(&mut self, gpio: &mut Gpio) {
+        if let Some(regs) = &self.regs {
+            regs.pwm.write(PWM_CTL, 0);
+        }
+
+        for pin in [12, 13, 18, 19] {
+            gpio.set_pwm_state(pin, None);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HwPwmForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    pub freq: u32,
+    pub duty: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HwPwmConfigForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    pub mode: PwmMode,
+    pub range: u32,
+    pub data: u32,
+    pub divisor: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HwPwmStopForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+}
+
+pub async fn start_hw_pwm(
+    State(appstate): State<AppState>,
+    Form(input): Form<HwPwmForm>,
+) -> Response {
+    // soft pwm would fight over the pin
+    let _ = appstate.pwm.stop(input.pin);
+
+    let mut hwpwm = appstate.hwpwm.lock().unwrap();
+    let mut gpio = appstate.gpio.lock().unwrap();
+
+    pins_changed(
+        match hwpwm.set(&mut gpio, input.pin, input.freq, input.duty) {
+            Ok(_) => log_info(
+                &appstate,
+                format!(
+                    "HW PWM GPIO {} at {}Hz {}%",
+                    input.pin, input.freq, input.duty
+                ),
+            ),
+            Err(e) => log_error(
+                &appstate,
+                format!("failed to start hw pwm on GPIO {}: {e}", input.pin),
+            ),
+        },
+    )
+}
+
+pub async fn configure_hw_pwm(
+    State(appstate): State<AppState>,
+    Form(input): Form<HwPwmConfigForm>,
+) -> Response {
+    let _ = appstate.pwm.stop(input.pin);
+
+    let mut hwpwm = appstate.hwpwm.lock().unwrap();
+    let mut gpio = appstate.gpio.lock().unwrap();
+
+    let result = match input.divisor {
+        Some(divisor) => hwpwm.set_divisor(divisor),
+        None => Ok(()),
+    }
+    .and_then(|_| {
+        hwpwm.configure(
+            &mut gpio,
+            input.pin,
+            input.mode,
+            input.range,
+            input.data,
+        )
+    });
+
+    pins_changed(match result {
+        Ok(_) => log_info(
+            &appstate,
+            format!(
+                "HW PWM GPIO {} {:?} range {} data {}",
+                input.pin, input.mode, input.range, input.data
+            ),
+        ),
+        Err(e) => log_error(
+            &appstate,
+            format!("failed to configure hw pwm on GPIO {}: {e}", input.pin),
+        ),
+    })
+}
+
+pub async fn stop_hw_pwm(
+    State(appstate): State<AppState>,
+    Form(input): Form<HwPwmStopForm>,
+) -> Response {
+    let mut hwpwm = appstate.hwpwm.lock().unwrap();
+    let mut gpio = appstate.gpio.lock().unwrap();
+
+    pins_changed(match hwpwm.stop(&mut gpio, input.pin) {
+        Ok(_) => {
+            log_info(&appstate, format!("Stopped HW PWM GPIO {}", input.pin))
+        }
+        Err(e) => log_error(
+            &appstate,
+            format!("failed to stop hw pwm on GPIO {}: {e}", input.pin),
+        ),
+    })
+}
@@ -0,0 +1,466 @@
+use crate::{
+    errors::GpioError,
+    hex::{parse_bytes, parse_u8, to_hex},
+    logger::{log_error, log_info},
+    AppState,
+};
+
+use axum::{extract::State, response::Html, Form};
+use nix::libc::{c_ulong, ioctl};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    os::fd::AsRawFd,
+};
+
+// I2C1 is the one broken out on GPIO 2/3
+pub const DEFAULT_I2C_BUS: u8 = 1;
+
+// linux/i2c-dev.h
+const I2C_RDWR: c_ulong = 0x0707;
+const I2C_M_RD: u16 = 0x0001;
+
+// everything outside is reserved by the spec
+pub const FIRST_ADDRESS: u8 = 0x03;
+pub const LAST_ADDRESS: u8 = 0x77;
+
+// i2c-dev refuses longer messages, and the
+// length has to fit the u16 in i2c_msg anyway
+pub const MAX_MESSAGE_LEN: usize = 8192;
+
+// anything that can do a combined write then read
+// so the helpers below can run against a fake bus
+pub trait I2cBus {
+    fn transfer(
+        &mut self,
+        addr: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), GpioError>;
+}
+
+#[repr(C)]
+struct I2cMsg {
+    addr: u16,
+    flags: u16,
+    len: u16,
+    buf: *mut u8,
+}
+
+#[repr(C)]
+struct I2cRdwrData {
+    msgs: *mut I2cMsg,
+    nmsgs: u32,
+}
+
+// /dev/i2c-N, needs the i2c group
+// or `dtparam=i2c_arm=on` for bus 1
+pub struct LinuxI2c {
+    file: File,
+    bus: u8,
+}
+
+impl LinuxI2c {
+    pub fn open(bus: u8) -> Result<Self, GpioError> {
+        let path = format!("/dev/i2c-{bus}");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| GpioError::I2c(format!("{path}: {e}")))?;
+
+        Ok(LinuxI2c { file, bus })
+    }
+}
+
+impl I2cBus for LinuxI2c {
+    // uses I2C_RDWR so the read comes after
+    // a repeated start instead of a stop
+    fn transfer(
+        &mut self,
+        addr: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), GpioError> {
+        let len = |buf: &[u8]| {
+            u16::try_from(buf.len()).map_err(|_| {
+                GpioError::I2c(format!("{} bytes is too long", buf.len()))
+            })
+        };
+
+        let mut write_buf = write.to_vec();
+        let mut msgs = Vec::with_capacity(2);
+
+        if !write_buf.is_empty() {
+            msgs.push(I2cMsg {
+                addr: addr as u16,
+                flags: 0,
+                len: len(&write_buf)?,
+                buf: write_buf.as_mut_ptr(),
+            });
+        }
+
+        if !read.is_empty() {
+            msgs.push(I2cMsg {
+                addr: addr as u16,
+                flags: I2C_M_RD,
+                len: len(read)?,
+                buf: read.as_mut_ptr(),
+            });
+        }
+
+        if msgs.is_empty() {
+            return Ok(());
+        }
+
+        let mut data = I2cRdwrData {
+            msgs: msgs.as_mut_ptr(),
+            nmsgs: msgs.len() as u32,
+        };
+
+        let ret = unsafe { ioctl(self.file.as_raw_fd(), I2C_RDWR, &mut data) };
+        if ret < 0 {
+            return Err(GpioError::I2c(format!(
+                "bus {} addr {addr:#04x}: {}",
+                self.bus,
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn check_len(len: usize) -> Result<(), GpioError> {
+    if len > MAX_MESSAGE_LEN {
+        return Err(GpioError::I2c(format!(
+            "{len} bytes is more than the {MAX_MESSAGE_LEN} a message can hold"
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_address(addr: u8) -> Result<(), GpioError> {
+    if !(FIRST_ADDRESS..=LAST_ADDRESS).contains(&addr) {
+        return Err(GpioError::I2c(format!(
+            "address {addr:#04x} is outside {FIRST_ADDRESS:#04x}-{LAST_ADDRESS:#04x}"
+        )));
+    }
+
+    Ok(())
+}
+
+// a device is there if it acks a single byte read
+// same as i2cdetect -r
+pub fn scan(bus: &mut dyn I2cBus) -> Vec<u8> {
+    let mut buf = [0u8; 1];
+
+    (FIRST_ADDRESS..=LAST_ADDRESS)
+        .filter(|addr| bus.transfer(*addr, &[], &mut buf).is_ok())
+        .collect()
+}
+
+pub fn read_register(
+    bus: &mut dyn I2cBus,
+    addr: u8,
+    reg: u8,
+    len: usize,
+) -> Result<Vec<u8>, GpioError> {
+    check_address(addr)?;
+    check_len(len)?;
+
+    let mut buf = vec![0u8; len];
+    bus.transfer(addr, &[reg], &mut buf)?;
+    Ok(buf)
+}
+
+pub fn write_register(
+    bus: &mut dyn I2cBus,
+    addr: u8,
+    reg: u8,
+    bytes: &[u8],
+) -> Result<(), GpioError> {
+    check_address(addr)?;
+    check_len(bytes.len() + 1)?;
+
+    let mut buf = Vec::with_capacity(bytes.len() + 1);
+    buf.push(reg);
+    buf.extend_from_slice(bytes);
+    bus.transfer(addr, &buf, &mut [])
+}
+
+pub fn raw_transfer(
+    bus: &mut dyn I2cBus,
+    addr: u8,
+    write: &[u8],
+    read_len: usize,
+) -> Result<Vec<u8>, GpioError> {
+    check_address(addr)?;
+    check_len(write.len())?;
+    check_len(read_len)?;
+
+    let mut buf = vec![0u8; read_len];
+    bus.transfer(addr, write, &mut buf)?;
+    Ok(buf)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct I2cScanForm {
+    pub bus: Option<u8>,
+}
+
+// addresses, registers and bytes are all strings
+// so they can be typed in as hex
+#[derive(Serialize, Deserialize, Debug)]
+pub struct I2cForm {
+    pub bus: Option<u8>,
+    pub addr: String,
+    pub reg: Option<String>,
+    #[serde(default)]
+    pub bytes: String,
+    pub len: Option<usize>,
+}
+
+fn parse_form(form: &I2cForm) -> Result<(u8, Option<u8>, Vec<u8>), String> {
+    let addr = parse_u8(&form.addr)?;
+    let reg = match form.reg.as_deref().map(str::trim) {
+        Some("") | None => None,
+        Some(reg) => Some(parse_u8(reg)?),
+    };
+    let bytes = parse_bytes(&form.bytes)?;
+
+    Ok((addr, reg, bytes))
+}
+
+pub async fn i2c_scan(
+    State(appstate): State<AppState>,
+    Form(input): Form<I2cScanForm>,
+) -> Html<String> {
+    let bus_num = input.bus.unwrap_or(DEFAULT_I2C_BUS);
+
+    // scanning all 117 addresses takes
+    // a while, keep it off the runtime
+    let found = tokio::task::spawn_blocking(move || {
+        LinuxI2c::open(bus_num).map(|mut bus| scan(&mut bus))
+    })
+    .await;
+
+    match found {
+        Ok(Ok(found)) if found.is_empty() => {
+            log_info(&appstate, format!("I2C bus {bus_num}: nothing found"))
+        }
+        Ok(Ok(found)) => {
+            let addrs: Vec<String> =
+                found.iter().map(|a| format!("{a:#04x}")).collect();
+            log_info(
+                &appstate,
+                format!("I2C bus {bus_num}: {}", addrs.join(", ")),
+            )
+        }
+        Ok(Err(e)) => log_error(&appstate, format!("I2C scan failed: {e}")),
+        Err(e) => log_error(&appstate, format!("I2C scan failed: {e}")),
+    }
+}
+
+pub async fn i2c_read(
+    State(appstate): State<AppState>,
+    Form(input): Form<I2cForm>,
+) -> Html<String> {
+    let bus_num = input.bus.unwrap_or(DEFAULT_I2C_BUS);
+    let len = input.len.unwrap_or(1);
+
+    let result = parse_form(&input).map_err(GpioError::I2c).and_then(
+        |(addr, reg, _)| {
+            let mut bus = LinuxI2c::open(bus_num)?;
+            match reg {
+                Some(reg) => read_register(&mut bus, addr, reg, len),
+                None => raw_transfer(&mut bus, addr, &[], len),
+            }
+        },
+    );
+
+    match result {
+        Ok(bytes) => log_info(
+            &appstate,
+            format!("I2C read {}: {}", input.addr, to_hex(&bytes)),
+        ),
+        Err(e) => log_error(&appstate, format!("I2C read failed: {e}")),
+    }
+}
+
+pub async fn i2c_write(
+    State(appstate): State<AppState>,
+    Form(input): Form<I2cForm>,
+) -> Html<String> {
+    let bus_num = input.bus.unwrap_or(DEFAULT_I2C_BUS);
+
+    let result = parse_form(&input).map_err(GpioError::I2c).and_then(
+        |(addr, reg, bytes)| {
+            let mut bus = LinuxI2c::open(bus_num)?;
+            match reg {
+                Some(reg) => write_register(&mut bus, addr, reg, &bytes),
+                None => raw_transfer(&mut bus, addr, &bytes, 0).map(|_| ()),
+            }
+        },
+    );
+
+    match result {
+        Ok(_) => log_info(
+            &appstate,
+            format!("I2C write {}: {}", input.addr, input.bytes),
+        ),
+        Err(e) => log_error(&appstate, format!("I2C write failed: {e}")),
+    }
+}
+
+// write then read with a repeated start
+pub async fn i2c_transfer(
+    State(appstate): State<AppState>,
+    Form(input): Form<I2cForm>,
+) -> Html<String> {
+    let bus_num = input.bus.unwrap_or(DEFAULT_I2C_BUS);
+    let len = input.len.unwrap_or(0);
+
+    let result = parse_form(&input).map_err(GpioError::I2c).and_then(
+        |(addr, reg, mut bytes)| {
+            if let Some(reg) = reg {
+                bytes.insert(0, reg);
+            }
+            let mut bus = LinuxI2c::open(bus_num)?;
+            raw_transfer(&mut bus, addr, &bytes, len)
+        },
+    );
+
+    match result {
+        Ok(bytes) => log_info(
+            &appstate,
+            format!("I2C transfer {}: {}", input.addr, to_hex(&bytes)),
+        ),
+        Err(e) => log_error(&appstate, format!("I2C transfer failed: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // devices with 256 registers behind an auto incrementing
+    // register pointer, like most sensors and eeproms
+    #[derive(Default)]
+    struct MockBus {
+        devices: HashMap<u8, ([u8; 256], u8)>,
+        transfers: usize,
+    }
+
+    impl MockBus {
+        fn with_devices(addrs: &[u8]) -> Self {
+            let mut bus = MockBus::default();
+            for addr in addrs {
+                let mut registers = [0u8; 256];
+                for (i, reg) in registers.iter_mut().enumerate() {
+                    *reg = i as u8 ^ *addr;
+                }
+                bus.devices.insert(*addr, (registers, 0));
+            }
+            bus
+        }
+    }
+
+    impl I2cBus for MockBus {
+        fn transfer(
+            &mut self,
+            addr: u8,
+            write: &[u8],
+            read: &mut [u8],
+        ) -> Result<(), GpioError> {
+            self.transfers += 1;
+            let Some((registers, pointer)) = self.devices.get_mut(&addr) else {
+                return Err(GpioError::I2c(format!("{addr:#04x} nack")));
+            };
+
+            if let Some((reg, bytes)) = write.split_first() {
+                *pointer = *reg;
+                for byte in bytes {
+                    registers[*pointer as usize] = *byte;
+                    *pointer = pointer.wrapping_add(1);
+                }
+            }
+
+            for byte in read.iter_mut() {
+                *byte = registers[*pointer as usize];
+                *pointer = pointer.wrapping_add(1);
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn scan_finds_the_devices_that_ack() {
+        let mut bus = MockBus::with_devices(&[0x20, 0x48, 0x76]);
+
+        assert_eq!(scan(&mut bus), vec![0x20, 0x48, 0x76]);
+        // every legal address got asked once
+        assert_eq!(bus.transfers, (LAST_ADDRESS - FIRST_ADDRESS + 1) as usize);
+    }
+
+    #[test]
+    fn scan_skips_reserved_addresses() {
+        let mut bus = MockBus::with_devices(&[0x00, 0x78, 0x50]);
+
+        assert_eq!(scan(&mut bus), vec![0x50]);
+    }
+
+    #[test]
+    fn read_register_reads_from_the_register() {
+        let mut bus = MockBus::with_devices(&[0x48]);
+
+        let bytes = read_register(&mut bus, 0x48, 0x10, 3).unwrap();
+        assert_eq!(bytes, vec![0x10 ^ 0x48, 0x11 ^ 0x48, 0x12 ^ 0x48]);
+    }
+
+    #[test]
+    fn write_register_then_read_it_back() {
+        let mut bus = MockBus::with_devices(&[0x50]);
+
+        write_register(&mut bus, 0x50, 0x20, &[0xde, 0xad, 0xbe]).unwrap();
+        let bytes = read_register(&mut bus, 0x50, 0x20, 3).unwrap();
+        assert_eq!(bytes, vec![0xde, 0xad, 0xbe]);
+
+        // neighbours are left alone
+        let before = read_register(&mut bus, 0x50, 0x1f, 1).unwrap();
+        assert_eq!(before, vec![0x1f ^ 0x50]);
+    }
+
+    #[test]
+    fn missing_device_is_an_error() {
+        let mut bus = MockBus::with_devices(&[0x48]);
+
+        assert!(read_register(&mut bus, 0x49, 0x00, 1).is_err());
+        assert!(write_register(&mut bus, 0x49, 0x00, &[1]).is_err());
+    }
+
+    #[test]
+    fn reserved_address_never_reaches_the_bus() {
+        let mut bus = MockBus::with_devices(&[0x00, 0x7f]);
+
+        assert!(read_register(&mut bus, 0x00, 0x00, 1).is_err());
+        assert!(write_register(&mut bus, 0x7f, 0x00, &[1]).is_err());
+        assert_eq!(bus.transfers, 0);
+    }
+
+    #[test]
+    fn oversized_len_is_refused_not_truncated() {
+        let mut bus = MockBus::with_devices(&[0x48]);
+
+        // 65537 would wrap to a 1 byte read as a u16
+        assert!(read_register(&mut bus, 0x48, 0x00, 65537).is_err());
+        assert!(raw_transfer(&mut bus, 0x48, &[], MAX_MESSAGE_LEN + 1).is_err());
+        assert_eq!(bus.transfers, 0);
+
+        let bytes = read_register(&mut bus, 0x48, 0x00, MAX_MESSAGE_LEN);
+        assert_eq!(bytes.unwrap().len(), MAX_MESSAGE_LEN);
+    }
+}
@@ -1,5 +1,12 @@
 use axum::response::Html;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
 
 use crate::AppState;
 
@@ -51,11 +58,99 @@ impl LogEntry {
     }
 }
 
+// things from before anyone could be listening, like a broken
+// config at boot. every new log panel gets them first thing
+static BOOT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// the log panels that are open already, for the ones that come
+// up later, like a config save conflict. once it's set nothing
+// new goes into BOOT, a panel opened after that missed them
+// the same way it missed everything else from before
+static LIVE: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+
+pub fn set_live(log_tx: broadcast::Sender<String>) {
+    let _ = LIVE.set(log_tx);
+}
+
+// everything that goes to the log panel, as plain lines
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+pub fn open_log_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+fn write_to_file(entry: &LogEntry) {
+    let Some(file) = LOG_FILE.get() else {
+        return;
+    };
+
+    let (hours, minutes, seconds) = entry.time;
+    let level = match entry.log_type {
+        LogType::Info => "INFO",
+        LogType::Error => "ERROR",
+    };
+
+    // a full disk shouldn't take the ui down with it
+    let _ = writeln!(
+        file.lock().unwrap(),
+        "[{:02}:{:02}:{:02}] {} {}",
+        hours,
+        minutes,
+        seconds,
+        level,
+        entry.message
+    );
+}
+
+fn log_pending(entry: LogEntry) {
+    println!("{}", entry.message);
+    write_to_file(&entry);
+    let html = entry.to_html().0;
+    match LIVE.get() {
+        Some(log_tx) => {
+            let _ = log_tx.send(html);
+        }
+        None => BOOT.lock().unwrap().push(html),
+    }
+}
+
+pub fn log_pending_error(message: impl Into<String>) {
+    log_pending(LogEntry::new(LogType::Error, message.into()));
+}
+
+pub fn log_pending_info(message: impl Into<String>) {
+    log_pending(LogEntry::new(LogType::Info, message.into()));
+}
+
+pub fn boot_messages() -> Vec<String> {
+    BOOT.lock().unwrap().clone()
+}
+
+// anything that came from outside, before it goes into the log panel
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// rides along in the log panel, htmx fires the events on
+// body when it lands, same as an HX-Trigger header would
+pub fn trigger_html(events: &[&str]) -> String {
+    let calls: Vec<String> = events
+        .iter()
+        .map(|event| format!("htmx.trigger(document.body, '{event}')"))
+        .collect();
+    format!(r#"<span hidden hx-on::load="{}"></span>"#, calls.join("; "))
+}
+
 pub fn log_error<E: std::fmt::Display>(
     appstate: &AppState,
     error: E,
 ) -> Html<String> {
     let entry = LogEntry::new(LogType::Error, format!("{}", error));
+    write_to_file(&entry);
     let html = entry.to_html();
     let _ = appstate.log_tx.send(html.0.clone());
     html
@@ -66,6 +161,7 @@ pub fn log_info(
     message: impl Into<String>,
 ) -> Html<String> {
     let entry = LogEntry::new(LogType::Info, message.into());
+    write_to_file(&entry);
     let html = entry.to_html();
     let _ = appstate.log_tx.send(html.0.clone());
     html
@@ -1,8 +1,32 @@
 mod actions;
+mod bitbang;
+mod cli;
 mod config;
+mod debounce;
 mod errors;
+mod format;
+mod gpclk;
 mod gpio;
+mod hex;
+mod hwpwm;
+mod i2c;
 mod logger;
+mod migrate;
+mod names;
+mod peripheral;
+mod polarity;
+mod profiles;
+mod pwm;
+mod sensors;
+mod servo;
+mod share;
+mod shutdown;
+mod simulate;
+mod spi;
+mod startup;
+mod timing;
+mod uart;
+mod watch;
 
 use actions::{
     add_action, delete_action, get_actions, start_actions, stop_actions, Action,
@@ -10,26 +34,56 @@ use actions::{
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        Request, State,
     },
-    http::header,
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
     response::{Html, IntoResponse, Response},
     routing::{any, delete, get, post},
     Router,
 };
+use bitbang::{shift_out_bytes, write_ws2812};
+use cli::Command;
 use config::Config;
+use debounce::{debounce_map, set_debounce};
+use errors::GpioError;
 use futures::{SinkExt, StreamExt};
+use gpclk::{disable_gpclk, enable_gpclk, GpClock};
 use gpio::{Gpio, PinLevel};
+use hwpwm::{configure_hw_pwm, start_hw_pwm, stop_hw_pwm, HwPwm};
+use i2c::{i2c_read, i2c_scan, i2c_transfer, i2c_write};
 use listenfd::ListenFd;
-use logger::{log_error, log_info};
+use logger::{boot_messages, log_error, log_info};
+use names::{resolve, set_pin_name};
+use polarity::set_inverted;
+use profiles::{list_profiles, switch_profile};
+use pwm::{pins_changed, start_pwm, stop_pwm, update_pwm, SoftPwm};
+use sensors::{
+    list_sensors, poll_sensor, sensor_reading, stop_sensor_poll, SensorPoller,
+};
+use servo::{configure_servo, remove_servo, servo_angle, servo_map, Servo};
+use share::{export_actions, export_config, import_actions, import_config};
+use shutdown::{
+    grace_period, install_panic_hook, safe_state, set_shutdown, shut_down,
+    until_shutdown, ShutdownPolicy,
+};
+use simulate::simulate_actions;
+use spi::{
+    run_spi_transfer, spi_devices, spi_transfer, SpiConfig, SpiTransferRequest,
+};
+use startup::{apply_startup, StartupConfig};
 use std::{
-    env,
+    collections::HashMap,
     error::Error,
+    future::IntoFuture,
     net::SocketAddr,
     ops::ControlFlow,
+    str::FromStr,
     sync::{atomic::AtomicBool, Arc, Mutex},
 };
+use timing::RtRunner;
 use tokio::{net::TcpListener, sync::broadcast};
+use uart::{close_uart, handle_uart_websocket, open_uart, Uart};
 
 #[derive(Clone)]
 struct AppState {
@@ -37,34 +91,125 @@ struct AppState {
     actions: Arc<Mutex<Vec<Action>>>,
     stop_it: Arc<AtomicBool>,
     log_tx: broadcast::Sender<String>,
+    rt: RtRunner,
+    pwm: SoftPwm,
+    hwpwm: Arc<Mutex<HwPwm>>,
+    servos: Arc<Mutex<HashMap<i32, Servo>>>,
+    gpclk: Arc<Mutex<GpClock>>,
+    uart: Uart,
+    sensors: SensorPoller,
+    debounce: Arc<Mutex<HashMap<i32, u32>>>,
+    startup: Arc<Mutex<StartupConfig>>,
+    shutdown: Arc<Mutex<Vec<ShutdownPolicy>>>,
+    // set once a signal came in, everything gets refused after
+    shutting_down: tokio::sync::watch::Sender<bool>,
+    read_only: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let port = env::args()
-        .nth(1)
-        .and_then(|arg| arg.parse::<i32>().ok())
-        .unwrap_or(3000);
-    let addr = format!("0.0.0.0:{port}");
+    let options = match cli::from_env_args() {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}", cli::usage());
+            return Ok(());
+        }
+        Ok(Command::Version) => {
+            println!("{}", cli::version());
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{e}, see pipin --help");
+            std::process::exit(2);
+        }
+    };
+    let addr = SocketAddr::new(options.bind, options.port);
+
+    if let Some(path) = options.config.clone() {
+        config::set_conf_path(path);
+    }
+    if let Some(profile) = &options.profile {
+        if let Err(e) = config::set_profile(profile) {
+            eprintln!("{e}, see pipin --help");
+            std::process::exit(2);
+        }
+    }
+    println!("using config {}", config::conf_path().display());
+
+    if let Some(path) = &options.log_file {
+        if let Err(e) = logger::open_log_file(path) {
+            eprintln!("failed to open log file {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
 
     let config = match config::load_conf() {
         Ok(conf) => conf,
-        Err(_) => {
-            println!("failed to load config");
-            Config {
-                actions: Vec::new(),
-            }
+        // already in the log panel, with the reason
+        Err(e) => {
+            println!("failed to load config: {e}");
+            Config::default()
         }
     };
 
+    if config.version > migrate::CONFIG_VERSION {
+        logger::log_pending_error(format!(
+            "config v{} is from a newer pipin (this one knows v{}), \
+             unknown fields are kept but may not work",
+            config.version,
+            migrate::CONFIG_VERSION
+        ));
+    }
+
+    config::mark_synced(&config);
     let (log_tx, _) = broadcast::channel::<String>(100);
+    logger::set_live(log_tx.clone());
+    names::set_names(config.pin_names);
+    let mut gpio = Gpio::new(options.backend);
+    for pin in config.inverted_pins {
+        let _ = gpio.set_inverted(pin, true);
+    }
+    let gpio = Arc::new(Mutex::new(gpio));
+    let shutting_down = tokio::sync::watch::Sender::new(false);
     let appstate = AppState {
-        gpio: Arc::new(Mutex::new(Gpio::new())),
+        pwm: SoftPwm::spawn(gpio.clone(), shutting_down.subscribe()),
+        hwpwm: Arc::new(Mutex::new(HwPwm::new(shutting_down.subscribe()))),
+        servos: Arc::new(Mutex::new(servo_map(config.servos))),
+        gpclk: Arc::new(Mutex::new(GpClock::new())),
+        uart: Uart::new(),
+        sensors: SensorPoller::default(),
+        debounce: Arc::new(Mutex::new(debounce_map(config.debounce))),
+        startup: Arc::new(Mutex::new(config.startup)),
+        shutdown: Arc::new(Mutex::new(config.shutdown)),
+        gpio,
         actions: Arc::new(Mutex::new(config.actions)),
         stop_it: Arc::new(AtomicBool::new(false)),
         log_tx,
+        rt: RtRunner::spawn(&config.timing),
+        shutting_down,
+        read_only: options.read_only,
     };
 
+    install_panic_hook(&appstate);
+
+    // read only leaves the pins to whoever else owns them
+    let auto_setup = appstate.startup.lock().unwrap().auto_setup;
+    if options.auto_setup || (auto_setup && !options.read_only) {
+        let _ = setup_gpio(&appstate);
+    }
+
+    for poll in config.sensor_polls {
+        appstate.sensors.start(&appstate, poll);
+    }
+
+    debounce::watch_inputs(appstate.clone());
+
+    // a hand edit would redrive inverted pins, so read only
+    // keeps whatever it started with
+    if !options.read_only {
+        watch::watch_config(appstate.clone());
+    }
+
     let app = Router::new()
         .route("/", get(serve_html))
         .route("/htmx.min.js", get(serve_js))
@@ -78,8 +223,49 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .route("/start-actions", post(start_actions))
         .route("/stop-actions", post(stop_actions))
         .route("/get-actions", get(get_actions))
+        .route("/simulate-actions", post(simulate_actions))
+        .route("/start-pwm", post(start_pwm))
+        .route("/update-pwm", post(update_pwm))
+        .route("/stop-pwm", post(stop_pwm))
+        .route("/start-hw-pwm", post(start_hw_pwm))
+        .route("/configure-hw-pwm", post(configure_hw_pwm))
+        .route("/stop-hw-pwm", post(stop_hw_pwm))
+        .route("/configure-servo", post(configure_servo))
+        .route("/servo-angle", post(servo_angle))
+        .route("/remove-servo", post(remove_servo))
+        .route("/enable-gpclk", post(enable_gpclk))
+        .route("/disable-gpclk", post(disable_gpclk))
+        .route("/i2c-scan", post(i2c_scan))
+        .route("/i2c-read", post(i2c_read))
+        .route("/i2c-write", post(i2c_write))
+        .route("/i2c-transfer", post(i2c_transfer))
+        .route("/spi-transfer", post(spi_transfer))
+        .route("/spi-devices", get(spi_devices))
+        .route("/uart-open", post(open_uart))
+        .route("/uart-close", post(close_uart))
+        .route("/uart-ws", any(handle_uart_websocket))
+        .route("/shift-out", post(shift_out_bytes))
+        .route("/ws2812", post(write_ws2812))
+        .route("/sensors", get(list_sensors))
+        .route("/sensor-read", get(sensor_reading))
+        .route("/poll-sensor", post(poll_sensor))
+        .route("/stop-sensor-poll", post(stop_sensor_poll))
+        .route("/set-debounce", post(set_debounce))
+        .route("/set-inverted", post(set_inverted))
+        .route("/set-pin-name", post(set_pin_name))
+        .route("/set-shutdown", post(set_shutdown))
+        .route("/profiles", get(list_profiles))
+        .route("/switch-profile", post(switch_profile))
+        .route("/export-actions", get(export_actions))
+        .route("/export-config", get(export_config))
+        .route("/import-actions", post(import_actions))
+        .route("/import-config", post(import_config))
         .route("/ws", any(handle_websocket))
-        .with_state(appstate);
+        .layer(middleware::from_fn_with_state(
+            appstate.clone(),
+            refuse_writes,
+        ))
+        .with_state(appstate.clone());
 
     let mut listenfd = ListenFd::from_env();
     let listener = match listenfd.take_tcp_listener(0)? {
@@ -90,19 +276,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
         None => TcpListener::bind(addr).await?,
     };
 
-    let shutdown = async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("failed to get ctrl+c signhandle");
-    };
-
+    // pins go to their safe state as soon as the signal lands,
+    // whatever is still open after that only gets the grace period
     println!("listening on {}", listener.local_addr()?);
-    axum::serve(
+    let state = appstate.clone();
+    let server = axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .with_graceful_shutdown(shutdown)
-    .await?;
+    .with_graceful_shutdown(async move { shut_down(&state).await });
+
+    tokio::select! {
+        result = server.into_future() => result?,
+        _ = grace_period(&appstate) => {}
+    }
 
     Ok(())
 }
@@ -125,20 +312,84 @@ async fn get_pins(State(appstate): State<AppState>) -> Html<String> {
     }
 }
 
-async fn setup(State(appstate): State<AppState>) -> impl IntoResponse {
+// read only still serves the ui and the logs, but anything
+// that isn't a plain GET, or is one of the GETs that drive
+// pins (a dht read pulls its pin low), gets turned away
+// before it reaches a handler
+async fn refuse_writes(
+    State(appstate): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let changes_state = request.method() != Method::GET
+        || matches!(
+            request.uri().path(),
+            "/setup" | "/reset" | "/terminate" | "/sensor-read"
+        );
+
+    if *appstate.shutting_down.borrow() {
+        let html = log_error(
+            &appstate,
+            format!("shutting down, refused {}", request.uri().path()),
+        );
+        return (StatusCode::SERVICE_UNAVAILABLE, html).into_response();
+    }
+
+    if appstate.read_only && changes_state {
+        let html = log_error(
+            &appstate,
+            format!("read only, refused {}", request.uri().path()),
+        );
+        return (StatusCode::FORBIDDEN, html).into_response();
+    }
+
+    next.run(request).await
+}
+
+// configured startup states go on right after
+// setup, whether from the button or at boot
+fn setup_gpio(appstate: &AppState) -> Html<String> {
     let mut gpio = appstate.gpio.lock().unwrap();
 
-    match gpio.setup() {
-        Ok(_) => log_info(&appstate, "GPIO initialized"),
-        Err(e) => {
-            println!("{e}");
-            log_error(&appstate, format!("failed to initialize gpio: {e}"))
-        }
+    if let Err(e) = gpio.setup() {
+        println!("{e}");
+        return log_error(appstate, format!("failed to initialize gpio: {e}"));
+    }
+
+    let startup = appstate.startup.lock().unwrap();
+    if startup.pins.is_empty() {
+        return log_info(appstate, "GPIO initialized");
     }
+
+    let errors = apply_startup(&mut gpio, &startup.pins);
+    if !errors.is_empty() {
+        return log_error(
+            appstate,
+            format!(
+                "GPIO initialized, failed startup states: {}",
+                errors.join(", ")
+            ),
+        );
+    }
+
+    log_info(
+        appstate,
+        format!(
+            "GPIO initialized, applied {} startup states",
+            startup.pins.len()
+        ),
+    )
+}
+
+async fn setup(State(appstate): State<AppState>) -> impl IntoResponse {
+    pins_changed(setup_gpio(&appstate))
 }
 
 async fn reset(State(appstate): State<AppState>) -> impl IntoResponse {
+    appstate.pwm.stop_all();
+    let mut hwpwm = appstate.hwpwm.lock().unwrap();
     let mut gpio = appstate.gpio.lock().unwrap();
+    hwpwm.stop_all(&mut gpio);
 
     match gpio.reset() {
         Ok(_) => log_info(&appstate, "GPIO reset"),
@@ -150,9 +401,18 @@ async fn reset(State(appstate): State<AppState>) -> impl IntoResponse {
 }
 
 async fn terminate(State(appstate): State<AppState>) -> impl IntoResponse {
+    // outputs would stay driven once the memory is unmapped
+    let errors = safe_state(&appstate);
     let mut gpio = appstate.gpio.lock().unwrap();
 
     match gpio.terminate() {
+        Ok(_) if !errors.is_empty() => log_error(
+            &appstate,
+            format!(
+                "GPIO terminated, failed shutdown states: {}",
+                errors.join(", ")
+            ),
+        ),
         Ok(_) => log_info(&appstate, "GPIO terminated"),
         Err(e) => {
             println!("{e}");
@@ -176,6 +436,16 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     let (mut sender, mut receiver) = socket.split();
 
     let mut send_task = tokio::spawn(async move {
+        for msg in boot_messages() {
+            let fmsg = format!(
+                r#"<div id="log-container" hx-swap-oob="afterbegin">{}</div>"#,
+                msg
+            );
+            if sender.send(Message::text(fmsg)).await.is_err() {
+                return;
+            }
+        }
+
         // separating tasks for logging
         loop {
             tokio::select! {
@@ -195,15 +465,22 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
         }
     });
 
+    let recv_state = state.clone();
     let mut recv_task = tokio::spawn(async move {
         while let Some(Ok(msg)) = receiver.next().await {
-            if process_message(msg, state.clone()).is_break() {
+            if process_message(msg, recv_state.clone()).is_break() {
                 break;
             }
         }
     });
 
     tokio::select! {
+        // dropping both halves closes the socket,
+        // graceful shutdown waits on it otherwise
+        _ = until_shutdown(&state) => {
+            send_task.abort();
+            recv_task.abort();
+        },
         rv_a = (&mut send_task) => {
             match rv_a {
                 Ok(_) => println!("messages sent"),
@@ -233,25 +510,129 @@ fn process_message(msg: Message, state: AppState) -> ControlFlow<(), ()> {
         return ControlFlow::Break(());
     }
 
+    // a toggle racing the signal can't undo the safe states
+    if *state.shutting_down.borrow() {
+        return ControlFlow::Break(());
+    }
+
     if let Message::Text(t) = msg {
-        if let Some(pin) = serde_json::from_str::<serde_json::Value>(&t)
-            .ok()
-            .and_then(|json| {
-                json.get("pin")
-                    .and_then(|val| val.as_str())
-                    .and_then(|s| s.parse::<i32>().ok())
-            })
-        {
+        if state.read_only {
+            let _ = log_error(&state, "read only, ignoring pin commands");
+            return ControlFlow::Continue(());
+        }
+
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(&t) else {
+            return ControlFlow::Continue(());
+        };
+
+        if let Some(pin) = json_pin(&json, "pin") {
             toggle_pin(pin, state)
+        } else if let Some(pin) = json_pin(&json, "pwm_stop") {
+            stop_pin_pwm(pin, state)
+        } else if let Some(pin) = json_pin(&json, "pwm_pin") {
+            let freq = json_field::<u32>(&json, "freq").unwrap_or(0);
+            let duty = json_field::<f32>(&json, "duty").unwrap_or(0.0);
+            set_pin_pwm(pin, freq, duty, state)
+        } else if let Some(data) = json_field::<String>(&json, "spi_data") {
+            spi_from_ws(&json, data, state)
         }
     }
 
     ControlFlow::Continue(())
 }
 
+// htmx sends everything as strings
+// but plain clients might not
+fn json_field<T: FromStr>(json: &serde_json::Value, key: &str) -> Option<T> {
+    match json.get(key)? {
+        serde_json::Value::String(s) => s.parse().ok(),
+        val => val.to_string().parse().ok(),
+    }
+}
+
+// a pin number or one of the configured names
+fn json_pin(json: &serde_json::Value, key: &str) -> Option<i32> {
+    match json.get(key)? {
+        serde_json::Value::String(s) => resolve(s).ok(),
+        val => val.to_string().parse().ok(),
+    }
+}
+
+fn set_pin_pwm(pin: i32, freq: u32, duty: f32, state: AppState) {
+    match state.pwm.set(pin, freq, duty) {
+        Ok(_) => {
+            let _ = log_info(
+                &state,
+                format!("PWM GPIO {} at {}Hz {}%", pin, freq, duty),
+            );
+        }
+        Err(e) => {
+            let _ = log_error(
+                &state,
+                format!("failed to start pwm on GPIO {}: {}", pin, e),
+            );
+        }
+    }
+}
+
+fn stop_pin_pwm(pin: i32, state: AppState) {
+    match state.pwm.stop(pin) {
+        Ok(_) => {
+            let _ = log_info(&state, format!("Stopped PWM GPIO {}", pin));
+        }
+        Err(e) => {
+            let _ = log_error(
+                &state,
+                format!("failed to stop pwm on GPIO {}: {}", pin, e),
+            );
+        }
+    }
+}
+
+fn spi_from_ws(json: &serde_json::Value, data: String, state: AppState) {
+    let defaults = SpiConfig::default();
+    let input = SpiTransferRequest {
+        bus: json_field(json, "bus").unwrap_or(0),
+        cs: json_field(json, "cs").unwrap_or(0),
+        config: SpiConfig {
+            mode: json_field(json, "mode").unwrap_or(defaults.mode),
+            speed_hz: json_field(json, "speed_hz").unwrap_or(defaults.speed_hz),
+            bits_per_word: json_field(json, "bits_per_word")
+                .unwrap_or(defaults.bits_per_word),
+        },
+        data,
+    };
+
+    // result is already in the log
+    let _ = run_spi_transfer(&state, &input);
+}
+
+// toggling under a running pwm would just get fought over,
+// so the pin is handed back first, low like a plain stop
+fn stop_pwm_for_toggle(pin: i32, state: &AppState) -> Result<(), GpioError> {
+    if state.pwm.stop(pin)? {
+        return Ok(());
+    }
+
+    let mut hwpwm = state.hwpwm.lock().unwrap();
+    let mut gpio = state.gpio.lock().unwrap();
+    match gpio.pwm(pin) {
+        Some(pwm) if pwm.hardware => hwpwm.stop(&mut gpio, pin),
+        _ => Ok(()),
+    }
+}
+
 fn toggle_pin(pin: i32, state: AppState) {
     println!("Toggling pin: {}", pin);
 
+    if let Err(e) = stop_pwm_for_toggle(pin, &state) {
+        let _ = log_error(
+            &state,
+            format!("failed to stop pwm on GPIO {}: {}", pin, e),
+        );
+        return;
+    }
+
     let mut gpio = match state.gpio.lock() {
         Ok(guard) => guard,
         Err(e) => {
@@ -0,0 +1,50 @@
+use serde_json::{Map, Value};
+
+// bump this and add a step to MIGRATIONS whenever a
+// saved config would stop loading, e.g. an Action
+// variant changing shape
+pub const CONFIG_VERSION: u32 = 1;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+// MIGRATIONS[n] takes a version n file to n + 1.
+// they work on the raw json so fields this build
+// doesn't know about go through untouched
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [v0_to_v1];
+
+// files from before there was a version. everything added since
+// came with a serde default, so there's nothing to rewrite yet
+fn v0_to_v1(_config: &mut Map<String, Value>) -> Result<(), String> {
+    Ok(())
+}
+
+fn version_of(config: &Value) -> u32 {
+    config
+        .get("version")
+        .and_then(Value::as_u64)
+        .map(|version| version as u32)
+        .unwrap_or(0)
+}
+
+// upgrades in place, returns the version it started from
+// if anything ran. newer files are left alone, the
+// fields we don't know get carried through on save
+pub fn migrate(config: &mut Value) -> Result<Option<u32>, String> {
+    let from = version_of(config);
+    if from >= CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    let Some(map) = config.as_object_mut() else {
+        return Err("config isn't a json object".to_string());
+    };
+
+    for (version, step) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        step(map).map_err(|e| {
+            format!("migrating v{} to v{}: {}", version, version + 1, e)
+        })?;
+        map.insert("version".to_string(), Value::from(version as u32 + 1));
+    }
+
+    Ok(Some(from))
+}
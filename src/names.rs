@@ -0,0 +1,206 @@
+use crate::{
+    config::save_pin_names,
+    errors::GpioError,
+    logger::{escape_html, log_error, log_info},
+    pwm::pins_changed,
+    AppState,
+};
+
+use axum::{extract::State, response::Response, Form};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{LazyLock, RwLock},
+};
+
+const MAX_NAME_LEN: usize = 32;
+
+// process wide rather than in AppState, form and json
+// deserialization needs it and has no way to get at state
+static PIN_NAMES: LazyLock<RwLock<BTreeMap<i32, String>>> =
+    LazyLock::new(|| RwLock::new(BTreeMap::new()));
+
+pub fn set_names(names: BTreeMap<i32, String>) {
+    *PIN_NAMES.write().unwrap() = names;
+}
+
+pub fn names() -> BTreeMap<i32, String> {
+    PIN_NAMES.read().unwrap().clone()
+}
+
+pub fn name_of(pin: i32) -> Option<String> {
+    PIN_NAMES.read().unwrap().get(&pin).cloned()
+}
+
+fn resolve_in(names: &BTreeMap<i32, String>, s: &str) -> Result<i32, String> {
+    let s = s.trim();
+    if let Ok(pin) = s.parse::<i32>() {
+        return Ok(pin);
+    }
+
+    names
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(s))
+        .map(|(pin, _)| *pin)
+        // the error ends up in the log panel
+        .ok_or_else(|| format!("Unknown pin `{}`", escape_html(s)))
+}
+
+// "17" or "pump", names are matched ignoring case
+pub fn resolve(s: &str) -> Result<i32, String> {
+    resolve_in(&PIN_NAMES.read().unwrap(), s)
+}
+
+// sections of a config that can name their pins
+const NAMED_SECTIONS: [&str; 3] = ["servos", "debounce", "shutdown"];
+
+// a config file is read before its pin_names are in use, so
+// its names get swapped for numbers against its own pin_names
+// rather than whatever was loaded before
+pub fn resolve_config_pins(config: &mut Value) -> Result<(), String> {
+    let names: BTreeMap<i32, String> = match config.get("pin_names") {
+        Some(names) => serde_json::from_value(names.clone())
+            .map_err(|e| format!("pin_names: {e}"))?,
+        None => BTreeMap::new(),
+    };
+
+    for section in NAMED_SECTIONS {
+        let Some(Value::Array(items)) = config.get_mut(section) else {
+            continue;
+        };
+
+        for item in items {
+            let Some(Value::String(name)) = item.get("pin") else {
+                continue;
+            };
+            let pin = resolve_in(&names, name)
+                .map_err(|e| format!("{section}: {e}"))?;
+            item["pin"] = Value::from(pin);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_name(pin: i32, name: &str) -> Result<(), String> {
+    check_name_in(&PIN_NAMES.read().unwrap(), pin, name)
+}
+
+fn check_name_in(
+    names: &BTreeMap<i32, String>,
+    pin: i32,
+    name: &str,
+) -> Result<(), String> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!("names are at most {MAX_NAME_LEN} characters"));
+    }
+
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("names can only use letters, digits, _ and -".to_string());
+    }
+
+    // would be read back as a pin number
+    if name.parse::<i32>().is_ok() {
+        return Err("names can't be plain numbers".to_string());
+    }
+
+    match resolve_in(names, name) {
+        Ok(other) if other != pin => {
+            Err(format!("`{name}` is already GPIO {other}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+// a whole set of names at once, e.g. from an imported config
+pub fn check_names(names: &BTreeMap<i32, String>) -> Result<(), String> {
+    for (pin, name) in names {
+        if !(0..=27).contains(pin) {
+            return Err(GpioError::InvalidPin(*pin).to_string());
+        }
+        check_name_in(names, *pin, name)?;
+    }
+    Ok(())
+}
+
+// for #[serde(deserialize_with)] on pin fields,
+// takes a number, a numeric string or a name
+pub fn deserialize_pin<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct PinVisitor;
+
+    impl de::Visitor<'_> for PinVisitor {
+        type Value = i32;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a pin number or name")
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<i32, E> {
+            i32::try_from(v).map_err(E::custom)
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<i32, E> {
+            i32::try_from(v).map_err(E::custom)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<i32, E> {
+            resolve(v).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(PinVisitor)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PinNameForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    // empty clears the name
+    pub name: String,
+}
+
+pub async fn set_pin_name(
+    State(appstate): State<AppState>,
+    Form(input): Form<PinNameForm>,
+) -> Response {
+    if !(0..=27).contains(&input.pin) {
+        return pins_changed(log_error(
+            &appstate,
+            GpioError::InvalidPin(input.pin),
+        ));
+    }
+
+    let name = input.name.trim().to_string();
+    if !name.is_empty() {
+        if let Err(e) = check_name(input.pin, &name) {
+            return pins_changed(log_error(&appstate, e));
+        }
+    }
+
+    {
+        let mut names = PIN_NAMES.write().unwrap();
+        match name.is_empty() {
+            true => names.remove(&input.pin),
+            false => names.insert(input.pin, name.clone()),
+        };
+    }
+
+    if let Err(e) = save_pin_names(&names()) {
+        let _ = log_error(&appstate, format!("Failed to save config: {}", e));
+    }
+
+    pins_changed(match name.is_empty() {
+        true => log_info(&appstate, format!("GPIO {} unnamed", input.pin)),
+        false => {
+            log_info(&appstate, format!("GPIO {} is now `{}`", input.pin, name))
+        }
+    })
+}
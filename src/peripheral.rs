@@ -0,0 +1,164 @@
+use crate::errors::GpioError;
+use nix::{
+    libc::{off_t, O_SYNC},
+    sys::mman::{mmap, munmap, MapFlags, ProtFlags},
+};
+use std::{
+    fs::{self, OpenOptions},
+    num::NonZero,
+    os::unix::fs::OpenOptionsExt,
+    ptr::{read_volatile, write_volatile, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+    thread::sleep,
+    time::Duration,
+};
+
+const BLOCK_SIZE: usize = 4096;
+const BLOCK_WORDS: usize = BLOCK_SIZE / 4;
+
+// pi 1 / zero, everything newer reports its
+// base through the device tree
+const FALLBACK_PERIPHERAL_BASE: u64 = 0x2000_0000;
+const BCM2711_PERIPHERAL_BASE: u64 = 0xFE00_0000;
+
+// offsets from the peripheral base
+pub const CLOCK_MANAGER_OFFSET: u64 = 0x10_1000;
+pub const PWM_OFFSET: u64 = 0x20_C000;
+
+// the clock manager ignores writes without this
+pub const CM_PASSWORD: u32 = 0x5A00_0000;
+pub const CM_ENAB: u32 = 1 << 4;
+const CM_BUSY: u32 = 1 << 7;
+
+// same idea as bcm_host_get_peripheral_address()
+// the ranges property is <child addr> <parent addr> <size>
+// where parent is either 32 or 64 bits depending on the soc
+pub fn detect_peripheral_base() -> Result<u64, GpioError> {
+    let ranges = match fs::read("/proc/device-tree/soc/ranges") {
+        Ok(ranges) => ranges,
+        Err(e) => {
+            println!("no device tree ranges ({e}), assuming bcm2835");
+            return Ok(FALLBACK_PERIPHERAL_BASE);
+        }
+    };
+
+    let word = |at: usize| -> Option<u64> {
+        let bytes = ranges.get(at..at + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?) as u64)
+    };
+
+    match word(4) {
+        Some(0) => word(8).ok_or(GpioError::HardwareDetection),
+        Some(base) => Ok(base),
+        None => Err(GpioError::HardwareDetection),
+    }
+}
+
+// oscillator that feeds the clock manager
+pub fn oscillator_hz(peripheral_base: u64) -> u32 {
+    if peripheral_base == BCM2711_PERIPHERAL_BASE {
+        54_000_000
+    } else {
+        19_200_000
+    }
+}
+
+// a word offset into a RegisterBlock, only made in consts
+// so one past the block is a build error rather than a
+// write into someone else's memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Register(usize);
+
+impl Register {
+    pub const fn at<const OFFSET: usize>() -> Self {
+        const { assert!(OFFSET < BLOCK_WORDS, "register outside the block") };
+        Register(OFFSET)
+    }
+}
+
+// a single mmapped 4k block out of /dev/mem
+// unlike /dev/gpiomem this needs root
+pub struct RegisterBlock {
+    map: AtomicPtr<u32>,
+}
+
+impl RegisterBlock {
+    pub fn map(address: u64) -> Result<Self, GpioError> {
+        let block_size = NonZero::new(BLOCK_SIZE).ok_or(GpioError::Setup)?;
+
+        let dev_mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_SYNC)
+            .open("/dev/mem")
+            .map_err(|e| {
+                println!("Failed to open /dev/mem: {e}");
+                GpioError::Setup
+            })?;
+
+        let map = unsafe {
+            mmap(
+                None,
+                block_size,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                dev_mem,
+                address as off_t,
+            )
+        }
+        .map_err(|e| {
+            println!("Failed to mmap {address:#x}: {e}");
+            GpioError::Setup
+        })?;
+
+        Ok(RegisterBlock {
+            map: AtomicPtr::new(map.cast::<u32>().as_ptr()),
+        })
+    }
+
+    // Register keeps the offset inside the mapping
+    fn register(&self, register: Register) -> *mut u32 {
+        unsafe { self.map.load(Ordering::SeqCst).add(register.0) }
+    }
+
+    pub fn read(&self, register: Register) -> u32 {
+        unsafe { read_volatile(self.register(register)) }
+    }
+
+    pub fn write(&self, register: Register, value: u32) {
+        unsafe { write_volatile(self.register(register), value) }
+    }
+}
+
+impl Drop for RegisterBlock {
+    fn drop(&mut self) {
+        let ptr = self.map.load(Ordering::SeqCst);
+
+        if let Some(non_null) = NonNull::new(ptr) {
+            unsafe {
+                munmap(non_null.cast(), BLOCK_SIZE).ok();
+            }
+        }
+    }
+}
+
+// clock manager generators have to be disabled and
+// idle before their source or divisor can change
+pub fn stop_clock(
+    clock: &RegisterBlock,
+    ctl: Register,
+) -> Result<(), GpioError> {
+    let value = clock.read(ctl);
+    clock.write(ctl, CM_PASSWORD | (value & !CM_ENAB & 0xFFF));
+
+    let mut tries = 0;
+    while clock.read(ctl) & CM_BUSY != 0 {
+        if tries > 100 {
+            return Err(GpioError::Clock("clock stuck busy".to_string()));
+        }
+        sleep(Duration::from_micros(10));
+        tries += 1;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use crate::{
+    config::save_inverted_pins,
+    errors::GpioError,
+    logger::{log_error, log_info},
+    names::deserialize_pin,
+    pwm::pins_changed,
+    AppState,
+};
+
+use axum::{extract::State, response::Response, Form};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvertForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    // checkbox, only sent when ticked
+    pub inverted: Option<String>,
+}
+
+pub fn inverted_pins(appstate: &AppState) -> Vec<i32> {
+    let gpio = appstate.gpio.lock().unwrap();
+    (0..=27).filter(|pin| gpio.is_inverted(*pin)).collect()
+}
+
+// flipping polarity keeps the logical level, so an
+// output that was on stays on and the wire flips
+pub async fn set_inverted(
+    State(appstate): State<AppState>,
+    Form(input): Form<InvertForm>,
+) -> Response {
+    if !(0..=27).contains(&input.pin) {
+        return pins_changed(log_error(
+            &appstate,
+            GpioError::InvalidPin(input.pin),
+        ));
+    }
+
+    let inverted = input.inverted.is_some();
+    let _ = appstate.pwm.stop(input.pin);
+    let result = appstate
+        .gpio
+        .lock()
+        .unwrap()
+        .set_inverted(input.pin, inverted);
+    if let Err(e) = result {
+        return pins_changed(log_error(&appstate, e));
+    }
+
+    if let Err(e) = save_inverted_pins(&inverted_pins(&appstate)) {
+        let _ = log_error(&appstate, format!("Failed to save config: {}", e));
+    }
+
+    pins_changed(match inverted {
+        true => {
+            log_info(&appstate, format!("GPIO {} is now active low", input.pin))
+        }
+        false => log_info(
+            &appstate,
+            format!("GPIO {} is now active high", input.pin),
+        ),
+    })
+}
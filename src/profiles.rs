@@ -0,0 +1,249 @@
+use crate::{
+    actions::Action,
+    config::{
+        active_profile, check_profile, conf_path, load_conf, mark_synced,
+        profile_path, profiles, read_profile, save_conf, set_profile, Config,
+        DEFAULT_PROFILE,
+    },
+    debounce::{debounce_map, DebounceConfig},
+    errors::GpioError,
+    logger::{log_error, log_info},
+    migrate::CONFIG_VERSION,
+    names,
+    servo::{servo_map, ServoConfig},
+    AppState,
+};
+
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse, Response},
+    Form,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+
+// every action has to pass, nothing is replaced otherwise
+pub fn validate_actions(
+    appstate: &AppState,
+    actions: &[Action],
+) -> Result<(), String> {
+    let gpio = appstate.gpio.lock().unwrap();
+    for (i, action) in actions.iter().enumerate() {
+        action
+            .validate(&gpio)
+            .map_err(|e| format!("action {} ({action}): {e}", i + 1))?;
+    }
+    Ok(())
+}
+
+// everything apply_config would act on, checked the way the
+// forms check it, so a bad file is refused as a whole
+pub fn validate_config(
+    appstate: &AppState,
+    config: &Config,
+) -> Result<(), String> {
+    validate_actions(appstate, &config.actions)?;
+
+    for servo in &config.servos {
+        if !(0..=27).contains(&servo.pin) {
+            return Err(GpioError::InvalidPin(servo.pin).to_string());
+        }
+        servo
+            .validate()
+            .map_err(|e| format!("servo on GPIO {}: {e}", servo.pin))?;
+    }
+
+    for debounce in &config.debounce {
+        debounce
+            .validate()
+            .map_err(|e| format!("debounce on GPIO {}: {e}", debounce.pin))?;
+    }
+
+    for poll in &config.sensor_polls {
+        poll.validate()
+            .map_err(|e| format!("sensor poll {}: {e}", poll.sensor))?;
+    }
+
+    config
+        .startup
+        .validate()
+        .map_err(|e| format!("startup: {e}"))?;
+
+    let pins = config
+        .shutdown
+        .iter()
+        .map(|policy| policy.pin)
+        .chain(config.inverted_pins.iter().copied());
+    for pin in pins {
+        if !(0..=27).contains(&pin) {
+            return Err(GpioError::InvalidPin(pin).to_string());
+        }
+    }
+
+    names::check_names(&config.pin_names).map_err(|e| format!("pin names: {e}"))
+}
+
+// swaps a freshly loaded config into the running state.
+// the timing section only takes effect on a restart
+// since the rt thread is set up once at boot
+pub fn apply_config(appstate: &AppState, config: Config) {
+    mark_synced(&config);
+
+    // a sequence from the old config shouldn't keep running
+    appstate.stop_it.store(true, Ordering::Relaxed);
+
+    names::set_names(config.pin_names);
+    *appstate.actions.lock().unwrap() = config.actions;
+    *appstate.servos.lock().unwrap() = servo_map(config.servos);
+    *appstate.debounce.lock().unwrap() = debounce_map(config.debounce);
+    *appstate.startup.lock().unwrap() = config.startup;
+    *appstate.shutdown.lock().unwrap() = config.shutdown;
+
+    {
+        let mut gpio = appstate.gpio.lock().unwrap();
+        for pin in 0..=27 {
+            let inverted = config.inverted_pins.contains(&pin);
+            if gpio.is_inverted(pin) != inverted {
+                if let Err(e) = gpio.set_inverted(pin, inverted) {
+                    let _ = log_error(appstate, format!("GPIO {pin}: {e}"));
+                }
+            }
+        }
+    }
+
+    for poll in appstate.sensors.polls() {
+        appstate.sensors.stop(&poll.sensor);
+    }
+    for poll in config.sensor_polls {
+        appstate.sensors.start(appstate, poll);
+    }
+}
+
+// the other way around, what's running as a config. timing and
+// unknown fields only live in the file, so they come from there
+pub fn current_config(appstate: &AppState) -> Config {
+    let file = read_profile(&active_profile()).unwrap_or_default();
+
+    let mut debounce: Vec<DebounceConfig> = appstate
+        .debounce
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(pin, settle_ms)| DebounceConfig {
+            pin: *pin,
+            settle_ms: *settle_ms,
+        })
+        .collect();
+    debounce.sort_by_key(|config| config.pin);
+
+    let inverted_pins = {
+        let gpio = appstate.gpio.lock().unwrap();
+        (0..=27).filter(|pin| gpio.is_inverted(*pin)).collect()
+    };
+
+    let mut servos: Vec<ServoConfig> = appstate
+        .servos
+        .lock()
+        .unwrap()
+        .values()
+        .map(|servo| servo.config.clone())
+        .collect();
+    servos.sort_by_key(|config| config.pin);
+
+    Config {
+        version: CONFIG_VERSION,
+        actions: appstate.actions.lock().unwrap().clone(),
+        servos,
+        sensor_polls: appstate.sensors.polls(),
+        debounce,
+        inverted_pins,
+        pin_names: names::names(),
+        startup: appstate.startup.lock().unwrap().clone(),
+        shutdown: appstate.shutdown.lock().unwrap().clone(),
+        ..file
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileForm {
+    pub profile: String,
+}
+
+pub async fn list_profiles() -> Html<String> {
+    let active = active_profile();
+    let options: String = profiles()
+        .into_iter()
+        .map(|profile| {
+            let selected = if profile == active { " selected" } else { "" };
+            format!(r#"<option value="{profile}"{selected}>{profile}</option>"#)
+        })
+        .collect();
+
+    Html(options)
+}
+
+// a profile that doesn't exist yet starts out
+// as a copy of the one that's active now
+pub async fn switch_profile(
+    State(appstate): State<AppState>,
+    Form(input): Form<ProfileForm>,
+) -> Response {
+    let name = input.profile.trim();
+    let previous = active_profile();
+    if name == previous {
+        return log_info(&appstate, format!("Already on profile {name}"))
+            .into_response();
+    }
+
+    if let Err(e) = check_profile(name) {
+        return log_error(&appstate, e).into_response();
+    }
+
+    let created = name != DEFAULT_PROFILE && !profile_path(Some(name)).exists();
+    let current = load_conf().unwrap_or_default();
+    let _ = set_profile(name);
+
+    if created {
+        if let Err(e) = save_conf(&current) {
+            let _ = set_profile(&previous);
+            return log_error(
+                &appstate,
+                format!("failed to create profile {name}: {e}"),
+            )
+            .into_response();
+        }
+    }
+
+    let config = match load_conf() {
+        Ok(config) => config,
+        Err(e) => {
+            let _ = set_profile(&previous);
+            return log_error(
+                &appstate,
+                format!("failed to load profile {name}: {e}"),
+            )
+            .into_response();
+        }
+    };
+    apply_config(&appstate, config);
+
+    let html = match created {
+        true => log_info(
+            &appstate,
+            format!("Created profile {name} from {previous}"),
+        ),
+        false => log_info(
+            &appstate,
+            format!("Switched to profile {name} ({})", conf_path().display()),
+        ),
+    };
+
+    (
+        [(
+            "HX-Trigger",
+            "pins-changed, actions-changed, profiles-changed",
+        )],
+        html,
+    )
+        .into_response()
+}
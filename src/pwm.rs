@@ -0,0 +1,316 @@
+use crate::{
+    errors::GpioError,
+    gpio::{Gpio, PinDirection, PinPwm},
+    logger::{log_error, log_info},
+    names::deserialize_pin,
+    AppState,
+};
+
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse, Response},
+    Form,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+// past this the edges are mostly scheduler noise
+pub const MAX_SOFT_PWM_FREQ: u32 = 10_000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PwmForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    pub freq: u32,
+    pub duty: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PwmStopForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+}
+
+struct Channel {
+    high_time: Duration,
+    low_time: Duration,
+    is_high: bool,
+    // None once a 0% or 100% duty has been written
+    next_edge: Option<Instant>,
+}
+
+impl Channel {
+    fn new(freq: u32, duty: f32) -> Self {
+        let period = Duration::from_secs_f64(1.0 / freq as f64);
+        let high_time = period.mul_f32(duty / 100.0);
+
+        Channel {
+            high_time,
+            low_time: period - high_time,
+            is_high: false,
+            next_edge: Some(Instant::now()),
+        }
+    }
+}
+
+type Channels = Arc<(Mutex<HashMap<i32, Channel>>, Condvar)>;
+
+// bit-bangs GPSET0/GPCLR0 from its own thread,
+// a single thread drives every pwm'd pin
+#[derive(Clone)]
+pub struct SoftPwm {
+    gpio: Arc<Mutex<Gpio>>,
+    channels: Channels,
+    // past the safe states nothing gets started again
+    shutting_down: watch::Receiver<bool>,
+}
+
+impl SoftPwm {
+    pub fn spawn(
+        gpio: Arc<Mutex<Gpio>>,
+        shutting_down: watch::Receiver<bool>,
+    ) -> Self {
+        let channels: Channels =
+            Arc::new((Mutex::new(HashMap::new()), Condvar::new()));
+
+        let thread_gpio = gpio.clone();
+        let thread_channels = channels.clone();
+        thread::Builder::new()
+            .name("pipin-pwm".to_string())
+            .spawn(move || run(thread_gpio, thread_channels))
+            .expect("failed to spawn soft pwm thread");
+
+        SoftPwm {
+            gpio,
+            channels,
+            shutting_down,
+        }
+    }
+
+    // starts pwm on a pin or updates it if it's already running
+    pub fn set(&self, pin: i32, freq: u32, duty: f32) -> Result<(), GpioError> {
+        if *self.shutting_down.borrow() {
+            return Err(GpioError::Pwm("shutting down".to_string()));
+        }
+        validate(freq, duty)?;
+
+        {
+            let mut gpio = self.gpio.lock().unwrap();
+            gpio.set_direction(pin, PinDirection::Output)?;
+            gpio.set_pwm_state(
+                pin,
+                Some(PinPwm {
+                    freq,
+                    duty,
+                    hardware: false,
+                }),
+            );
+        }
+
+        let (lock, cvar) = &*self.channels;
+        lock.lock().unwrap().insert(pin, Channel::new(freq, duty));
+        cvar.notify_one();
+
+        Ok(())
+    }
+
+    pub fn is_running(&self, pin: i32) -> bool {
+        self.channels.0.lock().unwrap().contains_key(&pin)
+    }
+
+    // stops pwm and leaves the pin low, i.e. off,
+    // which on an active low pin is a high wire
+    pub fn stop(&self, pin: i32) -> Result<bool, GpioError> {
+        let (lock, cvar) = &*self.channels;
+        let removed = lock.lock().unwrap().remove(&pin).is_some();
+        cvar.notify_one();
+
+        let mut gpio = self.gpio.lock().unwrap();
+        gpio.set_pwm_state(pin, None);
+        if removed {
+            gpio.write_levels(0, 1 << pin)?;
+        }
+
+        Ok(removed)
+    }
+
+    // needs to be called before the gpio
+    // gets reset or unmapped
+    pub fn stop_all(&self) {
+        let (lock, cvar) = &*self.channels;
+        let pins: Vec<i32> =
+            lock.lock().unwrap().drain().map(|(pin, _)| pin).collect();
+        cvar.notify_one();
+
+        let mut gpio = self.gpio.lock().unwrap();
+        let mut mask = 0;
+        for pin in pins {
+            gpio.set_pwm_state(pin, None);
+            mask |= 1 << pin;
+        }
+
+        let _ = gpio.write_levels(0, mask);
+    }
+}
+
+fn validate(freq: u32, duty: f32) -> Result<(), GpioError> {
+    if freq == 0 || freq > MAX_SOFT_PWM_FREQ {
+        return Err(GpioError::Pwm(format!(
+            "frequency must be between 1 and {MAX_SOFT_PWM_FREQ}Hz"
+        )));
+    }
+
+    if !(0.0..=100.0).contains(&duty) {
+        return Err(GpioError::Pwm(
+            "duty must be between 0 and 100%".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn run(gpio: Arc<Mutex<Gpio>>, channels: Channels) {
+    let (lock, cvar) = &*channels;
+
+    loop {
+        let mut set_mask = 0u32;
+        let mut clr_mask = 0u32;
+        let now = Instant::now();
+
+        let mut state = lock.lock().unwrap();
+        while state.is_empty() {
+            state = cvar.wait(state).unwrap();
+        }
+
+        for (pin, channel) in state.iter_mut() {
+            let Some(edge) = channel.next_edge else {
+                continue;
+            };
+
+            if edge > now {
+                continue;
+            }
+
+            if channel.low_time.is_zero() || channel.high_time.is_zero() {
+                // 100% or 0%, nothing to switch
+                match channel.low_time.is_zero() {
+                    true => set_mask |= 1 << pin,
+                    false => clr_mask |= 1 << pin,
+                }
+                channel.next_edge = None;
+                continue;
+            }
+
+            channel.is_high = !channel.is_high;
+            let hold = if channel.is_high {
+                set_mask |= 1 << pin;
+                channel.high_time
+            } else {
+                clr_mask |= 1 << pin;
+                channel.low_time
+            };
+
+            // schedule off the previous edge so we don't drift
+            // unless we fell so far behind it's pointless
+            let next = edge + hold;
+            channel.next_edge =
+                Some(if next < now { now + hold } else { next });
+        }
+
+        let next_edge = state.values().filter_map(|c| c.next_edge).min();
+        drop(state);
+
+        if set_mask != 0 || clr_mask != 0 {
+            let result = gpio.lock().unwrap().write_levels(set_mask, clr_mask);
+
+            if let Err(e) = result {
+                println!("soft pwm stopping: {e}");
+                let pins: Vec<i32> =
+                    lock.lock().unwrap().drain().map(|(pin, _)| pin).collect();
+
+                let mut gpio = gpio.lock().unwrap();
+                for pin in pins {
+                    gpio.set_pwm_state(pin, None);
+                }
+                continue;
+            }
+        }
+
+        // sleep until the next edge or until
+        // someone changes the channels
+        let state = lock.lock().unwrap();
+        match next_edge {
+            Some(edge) => {
+                let timeout = edge.saturating_duration_since(Instant::now());
+                drop(cvar.wait_timeout(state, timeout).unwrap());
+            }
+            None => {
+                drop(cvar.wait(state).unwrap());
+            }
+        }
+    }
+}
+
+pub async fn start_pwm(
+    State(appstate): State<AppState>,
+    Form(input): Form<PwmForm>,
+) -> Response {
+    match appstate.pwm.set(input.pin, input.freq, input.duty) {
+        Ok(_) => pins_changed(log_info(
+            &appstate,
+            format!(
+                "PWM GPIO {} at {}Hz {}%",
+                input.pin, input.freq, input.duty
+            ),
+        )),
+        Err(e) => pins_changed(log_error(
+            &appstate,
+            format!("failed to start pwm on GPIO {}: {e}", input.pin),
+        )),
+    }
+}
+
+pub async fn update_pwm(
+    State(appstate): State<AppState>,
+    Form(input): Form<PwmForm>,
+) -> Response {
+    if !appstate.pwm.is_running(input.pin) {
+        return pins_changed(log_error(
+            &appstate,
+            format!("PWM isn't running on GPIO {}", input.pin),
+        ));
+    }
+
+    start_pwm(State(appstate), Form(input)).await
+}
+
+pub async fn stop_pwm(
+    State(appstate): State<AppState>,
+    Form(input): Form<PwmStopForm>,
+) -> Response {
+    let pin = input.pin;
+    match appstate.pwm.stop(pin) {
+        Ok(true) => {
+            pins_changed(log_info(&appstate, format!("Stopped PWM GPIO {pin}")))
+        }
+        Ok(false) => pins_changed(log_error(
+            &appstate,
+            format!("PWM isn't running on GPIO {pin}"),
+        )),
+        Err(e) => pins_changed(log_error(
+            &appstate,
+            format!("failed to stop pwm on GPIO {pin}: {e}"),
+        )),
+    }
+}
+
+// lets the pin panel know it should refresh
+pub fn pins_changed(html: Html<String>) -> Response {
+    ([("HX-Trigger", "pins-changed")], html).into_response()
+}
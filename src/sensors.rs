@@ -0,0 +1,594 @@
+use crate::{
+    config::save_sensor_polls,
+    errors::GpioError,
+    gpio::{Gpio, PinDirection, PinLevel, PullType},
+    logger::{escape_html, log_error, log_info},
+    names::resolve,
+    timing::precise_sleep,
+    AppState,
+};
+
+use axum::{
+    extract::{Query, State},
+    response::Html,
+    Form, Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    fs,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{task::AbortHandle, time::sleep};
+
+const W1_DEVICES: &str = "/sys/bus/w1/devices";
+// DS18B20 family code
+const DS18B20_PREFIX: &str = "28-";
+
+// DHT22 needs 2s between reads, DHT11 gets by with 1s
+pub const MIN_POLL_SECS: u64 = 2;
+
+// longest any single DHT level should last
+const DHT_EDGE_TIMEOUT: Duration = Duration::from_micros(200);
+// high for ~27us means 0, ~70us means 1
+const DHT_ONE_THRESHOLD: Duration = Duration::from_micros(50);
+
+// written as "ds18b20", "ds18b20:28-0316a2794cff",
+// "dht11:4" or "dht22:17" in forms and the config
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Sensor {
+    // None reads the first one on the bus
+    Ds18b20(Option<String>),
+    Dht11(i32),
+    Dht22(i32),
+}
+
+impl FromStr for Sensor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg.trim())),
+            None => (s, None),
+        };
+
+        let pin = |arg: Option<&str>| {
+            arg.and_then(|pin| resolve(pin).ok())
+                .ok_or_else(|| format!("`{s}` needs a pin, e.g. {kind}:4"))
+        };
+
+        match kind.to_lowercase().as_str() {
+            "ds18b20" => {
+                let id = arg.filter(|id| !id.is_empty());
+                if let Some(id) = id {
+                    check_ds18b20_id(id)?;
+                }
+                Ok(Sensor::Ds18b20(id.map(str::to_string)))
+            }
+            "dht11" => Ok(Sensor::Dht11(pin(arg)?)),
+            "dht22" => Ok(Sensor::Dht22(pin(arg)?)),
+            _ => Err(format!("Unknown sensor `{s}`")),
+        }
+    }
+}
+
+impl TryFrom<String> for Sensor {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Sensor> for String {
+    fn from(sensor: Sensor) -> Self {
+        sensor.to_string()
+    }
+}
+
+impl Display for Sensor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sensor::Ds18b20(None) => write!(f, "ds18b20"),
+            Sensor::Ds18b20(Some(id)) => write!(f, "ds18b20:{id}"),
+            Sensor::Dht11(pin) => write!(f, "dht11:{pin}"),
+            Sensor::Dht22(pin) => write!(f, "dht22:{pin}"),
+        }
+    }
+}
+
+impl Sensor {
+    pub fn pin(&self) -> Option<i32> {
+        match self {
+            Sensor::Ds18b20(_) => None,
+            Sensor::Dht11(pin) | Sensor::Dht22(pin) => Some(*pin),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reading {
+    // celsius
+    pub temperature: f32,
+    // percent, only the DHTs have it
+    pub humidity: Option<f32>,
+}
+
+impl Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}C", self.temperature)?;
+        if let Some(humidity) = self.humidity {
+            write!(f, " {:.1}%RH", humidity)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+impl FromStr for Quantity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "temp" | "temperature" => Ok(Quantity::Temperature),
+            "humidity" | "rh" => Ok(Quantity::Humidity),
+            other => Err(format!("Invalid quantity `{other}`")),
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "above" | ">" => Ok(Comparison::Above),
+            "below" | "<" => Ok(Comparison::Below),
+            other => Err(format!("Invalid comparison `{other}`")),
+        }
+    }
+}
+
+impl Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quantity::Temperature => write!(f, "temperature"),
+            Quantity::Humidity => write!(f, "humidity"),
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Comparison::Above => write!(f, "above"),
+            Comparison::Below => write!(f, "below"),
+        }
+    }
+}
+
+impl Reading {
+    // Err when the sensor can't measure that at all
+    pub fn satisfies(
+        &self,
+        quantity: Quantity,
+        comparison: Comparison,
+        threshold: f32,
+    ) -> Result<bool, GpioError> {
+        let value = match quantity {
+            Quantity::Temperature => self.temperature,
+            Quantity::Humidity => self.humidity.ok_or_else(|| {
+                GpioError::Sensor("sensor has no humidity".to_string())
+            })?,
+        };
+
+        Ok(match comparison {
+            Comparison::Above => value > threshold,
+            Comparison::Below => value < threshold,
+        })
+    }
+}
+
+// the id ends up in a sysfs path, so nothing but
+// the family code and the hex serial gets through
+fn check_ds18b20_id(id: &str) -> Result<(), String> {
+    let bad = escape_html(id);
+    let serial = id.strip_prefix(DS18B20_PREFIX).ok_or_else(|| {
+        format!("`{bad}` isn't a DS18B20 id, they start with {DS18B20_PREFIX}")
+    })?;
+
+    if serial.is_empty() || !serial.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "`{bad}` isn't a DS18B20 id, e.g. 28-0316a2794cff"
+        ));
+    }
+
+    Ok(())
+}
+
+// ids of everything the w1-gpio overlay found
+pub fn ds18b20_devices() -> Vec<String> {
+    let mut ids: Vec<String> = fs::read_dir(W1_DEVICES)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| name.starts_with(DS18B20_PREFIX))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ids.sort();
+    ids
+}
+
+// w1_slave looks like
+// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+// 72 01 4b 46 7f ff 0e 10 57 t=23125
+// blocks for the ~750ms conversion
+fn read_ds18b20(id: Option<&str>) -> Result<Reading, GpioError> {
+    let id = match id {
+        Some(id) => {
+            check_ds18b20_id(id).map_err(GpioError::Sensor)?;
+            id.to_string()
+        }
+        None => ds18b20_devices().into_iter().next().ok_or_else(|| {
+            GpioError::Sensor(format!("no DS18B20 under {W1_DEVICES}"))
+        })?,
+    };
+
+    let path = format!("{W1_DEVICES}/{id}/w1_slave");
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| GpioError::Sensor(format!("{path}: {e}")))?;
+
+    let mut lines = contents.lines();
+    if !lines
+        .next()
+        .is_some_and(|line| line.trim_end().ends_with("YES"))
+    {
+        return Err(GpioError::Sensor(format!("{id}: crc check failed")));
+    }
+
+    let millis = lines
+        .next()
+        .and_then(|line| line.split("t=").nth(1))
+        .and_then(|t| t.trim().parse::<i32>().ok())
+        .ok_or_else(|| GpioError::Sensor(format!("{id}: no temperature")))?;
+
+    Ok(Reading {
+        temperature: millis as f32 / 1000.0,
+        humidity: None,
+    })
+}
+
+// how long the pin stays at level, Err if it never leaves
+fn level_duration(
+    gpio: &Gpio,
+    pin: i32,
+    level: PinLevel,
+) -> Result<Duration, GpioError> {
+    let start = Instant::now();
+
+    while gpio.get_physical_level(pin)? == level {
+        if start.elapsed() > DHT_EDGE_TIMEOUT {
+            return Err(GpioError::Sensor(format!(
+                "GPIO {pin} stuck {level}, is a DHT connected?"
+            )));
+        }
+    }
+
+    Ok(start.elapsed())
+}
+
+// single wire protocol, we pull the line low to ask for a
+// reading then time 40 bits worth of high pulses. all of it
+// is voltages, so an active low setting on the pin is ignored
+fn read_dht(
+    gpio: &mut Gpio,
+    sensor: &Sensor,
+    spin: Duration,
+) -> Result<Reading, GpioError> {
+    let (pin, start_low) = match sensor {
+        Sensor::Dht11(pin) => (*pin, Duration::from_millis(20)),
+        Sensor::Dht22(pin) => (*pin, Duration::from_micros(1100)),
+        Sensor::Ds18b20(_) => {
+            return Err(GpioError::Sensor("not a DHT".to_string()));
+        }
+    };
+
+    gpio.set_pull_type(pin, PullType::Up)?;
+    gpio.set_physical_level(pin, PinLevel::Low)?;
+    precise_sleep(start_low, spin);
+    gpio.set_direction(pin, PinDirection::Input)?;
+
+    // sensor answers with 80us low then 80us high
+    level_duration(gpio, pin, PinLevel::High)?;
+    level_duration(gpio, pin, PinLevel::Low)?;
+    level_duration(gpio, pin, PinLevel::High)?;
+
+    let mut bytes = [0u8; 5];
+    for i in 0..40 {
+        level_duration(gpio, pin, PinLevel::Low)?;
+        let high = level_duration(gpio, pin, PinLevel::High)?;
+        if high > DHT_ONE_THRESHOLD {
+            bytes[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+
+    let sum = bytes[..4].iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    if sum != bytes[4] {
+        return Err(GpioError::Sensor(format!(
+            "{sensor}: checksum mismatch, try realtime timing"
+        )));
+    }
+
+    let reading = match sensor {
+        Sensor::Dht11(_) => Reading {
+            humidity: Some(bytes[0] as f32 + bytes[1] as f32 / 10.0),
+            temperature: bytes[2] as f32 + (bytes[3] & 0x7f) as f32 / 10.0,
+        },
+        _ => {
+            let humidity = u16::from_be_bytes([bytes[0], bytes[1]]);
+            let temp = u16::from_be_bytes([bytes[2] & 0x7f, bytes[3]]);
+            let sign = if bytes[2] & 0x80 != 0 { -1.0 } else { 1.0 };
+            Reading {
+                humidity: Some(humidity as f32 / 10.0),
+                temperature: sign * temp as f32 / 10.0,
+            }
+        }
+    };
+
+    Ok(reading)
+}
+
+pub async fn read_sensor(
+    appstate: &AppState,
+    sensor: &Sensor,
+) -> Result<Reading, GpioError> {
+    let sensor = sensor.clone();
+
+    match &sensor {
+        Sensor::Ds18b20(id) => {
+            let id = id.clone();
+            tokio::task::spawn_blocking(move || read_ds18b20(id.as_deref()))
+                .await
+                .map_err(|e| GpioError::Sensor(e.to_string()))?
+        }
+        Sensor::Dht11(_) | Sensor::Dht22(_) => {
+            let gpio = appstate.gpio.clone();
+            let spin = appstate.rt.spin();
+
+            // bit timings need the rt thread
+            appstate
+                .rt
+                .run(move || read_dht(&mut gpio.lock().unwrap(), &sensor, spin))
+                .await
+                .unwrap_or_else(|| {
+                    Err(GpioError::Sensor("rt runner stopped".to_string()))
+                })
+        }
+    }
+}
+
+// polls until the reading crosses threshold, None if
+// the sequence got stopped first. read errors are logged
+// and retried since DHTs drop the odd reading
+pub async fn wait_for_sensor(
+    appstate: &AppState,
+    sensor: &Sensor,
+    quantity: Quantity,
+    comparison: Comparison,
+    threshold: f32,
+    stop: &AtomicBool,
+) -> Result<Option<Reading>, GpioError> {
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        match read_sensor(appstate, sensor).await {
+            Ok(reading) => {
+                if reading.satisfies(quantity, comparison, threshold)? {
+                    return Ok(Some(reading));
+                }
+            }
+            Err(e) => {
+                let _ = log_error(appstate, format!("{sensor}: {e}, retrying"));
+            }
+        }
+
+        sleep(Duration::from_secs(MIN_POLL_SECS)).await;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SensorPoll {
+    pub sensor: Sensor,
+    pub interval_s: u64,
+}
+
+impl SensorPoll {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_s < MIN_POLL_SECS {
+            return Err(format!(
+                "poll interval must be at least {MIN_POLL_SECS}s"
+            ));
+        }
+
+        match self.sensor {
+            Sensor::Dht11(pin) | Sensor::Dht22(pin)
+                if !(0..=27).contains(&pin) =>
+            {
+                Err(GpioError::InvalidPin(pin).to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// background reads that land in the log panel
+#[derive(Clone, Default)]
+pub struct SensorPoller {
+    polls: Arc<Mutex<HashMap<String, (SensorPoll, AbortHandle)>>>,
+}
+
+impl SensorPoller {
+    pub fn start(&self, appstate: &AppState, poll: SensorPoll) {
+        let state = appstate.clone();
+        let sensor = poll.sensor.clone();
+        let interval = Duration::from_secs(poll.interval_s.max(MIN_POLL_SECS));
+
+        let task = tokio::spawn(async move {
+            loop {
+                match read_sensor(&state, &sensor).await {
+                    Ok(reading) => {
+                        let _ =
+                            log_info(&state, format!("{sensor}: {reading}"));
+                    }
+                    Err(e) => {
+                        let _ = log_error(&state, format!("{sensor}: {e}"));
+                    }
+                }
+                sleep(interval).await;
+            }
+        });
+
+        let key = poll.sensor.to_string();
+        let old = self
+            .polls
+            .lock()
+            .unwrap()
+            .insert(key, (poll, task.abort_handle()));
+        if let Some((_, handle)) = old {
+            handle.abort();
+        }
+    }
+
+    pub fn stop(&self, sensor: &Sensor) -> bool {
+        match self.polls.lock().unwrap().remove(&sensor.to_string()) {
+            Some((_, handle)) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn polls(&self) -> Vec<SensorPoll> {
+        self.polls
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(poll, _)| poll.clone())
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SensorQuery {
+    pub sensor: Sensor,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SensorResponse {
+    pub ok: bool,
+    pub sensor: String,
+    pub reading: Option<Reading>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SensorList {
+    pub ds18b20: Vec<String>,
+    pub polling: Vec<SensorPoll>,
+}
+
+pub async fn list_sensors(
+    State(appstate): State<AppState>,
+) -> Json<SensorList> {
+    Json(SensorList {
+        ds18b20: ds18b20_devices(),
+        polling: appstate.sensors.polls(),
+    })
+}
+
+pub async fn sensor_reading(
+    State(appstate): State<AppState>,
+    Query(input): Query<SensorQuery>,
+) -> Json<SensorResponse> {
+    let sensor = input.sensor.to_string();
+
+    Json(match read_sensor(&appstate, &input.sensor).await {
+        Ok(reading) => {
+            let _ = log_info(&appstate, format!("{sensor}: {reading}"));
+            SensorResponse {
+                ok: true,
+                sensor,
+                reading: Some(reading),
+                error: None,
+            }
+        }
+        Err(e) => {
+            let _ = log_error(&appstate, format!("{sensor}: {e}"));
+            SensorResponse {
+                ok: false,
+                sensor,
+                reading: None,
+                error: Some(e.to_string()),
+            }
+        }
+    })
+}
+
+fn save(appstate: &AppState) {
+    if let Err(e) = save_sensor_polls(&appstate.sensors.polls()) {
+        let _ = log_error(appstate, format!("Failed to save config: {}", e));
+    }
+}
+
+pub async fn poll_sensor(
+    State(appstate): State<AppState>,
+    Form(input): Form<SensorPoll>,
+) -> Html<String> {
+    if let Err(e) = input.validate() {
+        return log_error(&appstate, e);
+    }
+
+    let message =
+        format!("Polling {} every {}s", input.sensor, input.interval_s);
+    appstate.sensors.start(&appstate, input);
+    save(&appstate);
+
+    log_info(&appstate, message)
+}
+
+pub async fn stop_sensor_poll(
+    State(appstate): State<AppState>,
+    Form(input): Form<SensorQuery>,
+) -> Html<String> {
+    if !appstate.sensors.stop(&input.sensor) {
+        return log_error(&appstate, format!("not polling {}", input.sensor));
+    }
+
+    save(&appstate);
+    log_info(&appstate, format!("Stopped polling {}", input.sensor))
+}
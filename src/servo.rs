@@ -0,0 +1,308 @@
+use crate::{
+    config::save_servos,
+    errors::GpioError,
+    hwpwm::pwm_pin,
+    logger::{log_error, log_info},
+    names::deserialize_pin,
+    pwm::pins_changed,
+    AppState,
+};
+
+use axum::{extract::State, response::Response, Form};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::HashMap, time::Duration};
+use tokio::time::sleep;
+
+// hobby servos want a pulse every 20ms
+pub const SERVO_FREQ: u32 = 50;
+const SERVO_PERIOD_US: f32 = 1_000_000.0 / SERVO_FREQ as f32;
+
+// one step per pwm period, going faster
+// than that doesn't move the horn any sooner
+const SLEW_STEP: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServoConfig {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    #[serde(default = "default_min_pulse")]
+    pub min_pulse_us: u32,
+    #[serde(default = "default_max_pulse")]
+    pub max_pulse_us: u32,
+    #[serde(default)]
+    pub min_angle: f32,
+    #[serde(default = "default_max_angle")]
+    pub max_angle: f32,
+}
+
+fn default_min_pulse() -> u32 {
+    1000
+}
+
+fn default_max_pulse() -> u32 {
+    2000
+}
+
+fn default_max_angle() -> f32 {
+    180.0
+}
+
+impl ServoConfig {
+    pub fn validate(&self) -> Result<(), GpioError> {
+        if self.min_pulse_us >= self.max_pulse_us
+            || self.max_pulse_us as f32 >= SERVO_PERIOD_US
+        {
+            return Err(GpioError::Servo(format!(
+                "pulse widths must satisfy min < max < {SERVO_PERIOD_US}us"
+            )));
+        }
+
+        if self.min_angle.partial_cmp(&self.max_angle) != Some(Ordering::Less) {
+            return Err(GpioError::Servo(
+                "min angle must be below max angle".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // linear between the two endpoints
+    pub fn pulse_for(&self, angle: f32) -> Result<f32, GpioError> {
+        if !(self.min_angle..=self.max_angle).contains(&angle) {
+            return Err(GpioError::Servo(format!(
+                "{angle} is outside {}..{} degrees",
+                self.min_angle, self.max_angle
+            )));
+        }
+
+        let span = (angle - self.min_angle) / (self.max_angle - self.min_angle);
+        let pulses = (self.max_pulse_us - self.min_pulse_us) as f32;
+
+        Ok(self.min_pulse_us as f32 + span * pulses)
+    }
+}
+
+pub struct Servo {
+    pub config: ServoConfig,
+    // unknown until we've driven it once
+    angle: Option<f32>,
+    // bumped on every move so a newer
+    // move can cut an older one short
+    generation: u64,
+}
+
+impl Servo {
+    pub fn new(config: ServoConfig) -> Self {
+        Servo {
+            config,
+            angle: None,
+            generation: 0,
+        }
+    }
+}
+
+pub fn servo_map(configs: Vec<ServoConfig>) -> HashMap<i32, Servo> {
+    configs
+        .into_iter()
+        .map(|config| (config.pin, Servo::new(config)))
+        .collect()
+}
+
+// hardware pwm when the pin has it and /dev/mem could be
+// mapped, otherwise fall back to the soft pwm thread
+fn write_pulse(
+    appstate: &AppState,
+    pin: i32,
+    pulse_us: f32,
+) -> Result<(), GpioError> {
+    let duty = pulse_us / SERVO_PERIOD_US * 100.0;
+
+    if pwm_pin(pin).is_some() {
+        let mut hwpwm = appstate.hwpwm.lock().unwrap();
+        if hwpwm.available() {
+            let mut gpio = appstate.gpio.lock().unwrap();
+            return hwpwm.set(&mut gpio, pin, SERVO_FREQ, duty);
+        }
+    }
+
+    appstate.pwm.set(pin, SERVO_FREQ, duty)
+}
+
+// every move in flight stops at its next step, for
+// the stop button and before the safe states go on
+pub fn cancel_moves(appstate: &AppState) {
+    for servo in appstate.servos.lock().unwrap().values_mut() {
+        servo.generation += 1;
+    }
+}
+
+// moves to angle over duration, or straight
+// there if we don't know where the servo is
+pub async fn move_servo(
+    appstate: &AppState,
+    pin: i32,
+    angle: f32,
+    duration: Duration,
+) -> Result<(), GpioError> {
+    let (config, from, generation) = {
+        let mut servos = appstate.servos.lock().unwrap();
+        let servo = servos.get_mut(&pin).ok_or_else(|| {
+            GpioError::Servo(format!("no servo configured on GPIO {pin}"))
+        })?;
+
+        servo.generation += 1;
+        (servo.config.clone(), servo.angle, servo.generation)
+    };
+
+    // check the target before we start moving
+    config.pulse_for(angle)?;
+
+    let steps = match from {
+        Some(_) => (duration.as_millis() / SLEW_STEP.as_millis()).max(1),
+        None => 1,
+    };
+    let from = from.unwrap_or(angle);
+
+    for step in 1..=steps {
+        let current = from + (angle - from) * step as f32 / steps as f32;
+
+        // the pulse goes out under the lock, so once cancel_moves
+        // has returned no step can land after the pwm is stopped
+        {
+            let mut servos = appstate.servos.lock().unwrap();
+            match servos.get_mut(&pin) {
+                Some(servo) if servo.generation == generation => {
+                    servo.angle = Some(current);
+                }
+                // removed, cancelled or a newer move took over
+                _ => return Ok(()),
+            }
+
+            write_pulse(appstate, pin, config.pulse_for(current)?)?;
+        }
+
+        if step < steps {
+            sleep(SLEW_STEP).await;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServoAngleForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    pub angle: f32,
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServoRemoveForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+}
+
+fn save(appstate: &AppState) {
+    let configs: Vec<ServoConfig> = appstate
+        .servos
+        .lock()
+        .unwrap()
+        .values()
+        .map(|servo| servo.config.clone())
+        .collect();
+
+    if let Err(e) = save_servos(&configs) {
+        let _ = log_error(appstate, format!("Failed to save config: {}", e));
+    }
+}
+
+pub async fn configure_servo(
+    State(appstate): State<AppState>,
+    Form(input): Form<ServoConfig>,
+) -> Response {
+    if let Err(e) = input.validate() {
+        return pins_changed(log_error(
+            &appstate,
+            format!("failed to configure servo on GPIO {}: {e}", input.pin),
+        ));
+    }
+
+    let pin = input.pin;
+    appstate
+        .servos
+        .lock()
+        .unwrap()
+        .insert(pin, Servo::new(input));
+    save(&appstate);
+
+    pins_changed(log_info(&appstate, format!("Servo on GPIO {pin}")))
+}
+
+pub async fn servo_angle(
+    State(appstate): State<AppState>,
+    Form(input): Form<ServoAngleForm>,
+) -> Response {
+    let duration = Duration::from_millis(input.duration_ms.unwrap_or(0));
+
+    // don't hold the request open for slow moves
+    let state = appstate.clone();
+    tokio::spawn(async move {
+        if let Err(e) =
+            move_servo(&state, input.pin, input.angle, duration).await
+        {
+            let _ = log_error(
+                &state,
+                format!("failed to move servo on GPIO {}: {e}", input.pin),
+            );
+        }
+    });
+
+    pins_changed(log_info(
+        &appstate,
+        format!(
+            "Servo GPIO {} -> {} degrees over {}ms",
+            input.pin,
+            input.angle,
+            duration.as_millis()
+        ),
+    ))
+}
+
+pub async fn remove_servo(
+    State(appstate): State<AppState>,
+    Form(input): Form<ServoRemoveForm>,
+) -> Response {
+    let removed = appstate.servos.lock().unwrap().remove(&input.pin);
+
+    if removed.is_none() {
+        return pins_changed(log_error(
+            &appstate,
+            format!("no servo configured on GPIO {}", input.pin),
+        ));
+    }
+
+    // leave the pin idle rather than holding the last
+    // position. a hw pwm pin may have fallen back to soft
+    let stopped = match appstate.pwm.stop(input.pin) {
+        Ok(false) if pwm_pin(input.pin).is_some() => {
+            let mut hwpwm = appstate.hwpwm.lock().unwrap();
+            let mut gpio = appstate.gpio.lock().unwrap();
+            hwpwm.stop(&mut gpio, input.pin)
+        }
+        stopped => stopped.map(|_| ()),
+    };
+
+    if let Err(e) = stopped {
+        let _ = log_error(
+            &appstate,
+            format!("failed to stop pwm on GPIO {}: {e}", input.pin),
+        );
+    }
+
+    save(&appstate);
+    pins_changed(log_info(
+        &appstate,
+        format!("Removed servo on GPIO {}", input.pin),
+    ))
+}
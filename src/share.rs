@@ -0,0 +1,325 @@
+use crate::{
+    actions::Action,
+    config::{
+        active_profile, check_profile, parse_upload, read_profile,
+        save_actions, save_conf, Config,
+    },
+    logger::{escape_html, log_error, log_info},
+    migrate::CONFIG_VERSION,
+    profiles::{
+        apply_config, current_config, validate_actions, validate_config,
+    },
+    AppState,
+};
+
+use axum::{
+    extract::{Multipart, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+// a sequence file is a config with nothing but the actions,
+// so either kind of file can be uploaded as a sequence
+#[derive(Serialize)]
+struct Sequence<'a> {
+    version: u32,
+    actions: &'a [Action],
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportQuery {
+    // the active one when left out
+    pub profile: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ImportMode {
+    // added after the current sequence
+    #[default]
+    Merge,
+    Replace,
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "merge" => Ok(ImportMode::Merge),
+            "replace" => Ok(ImportMode::Replace),
+            other => Err(format!("unknown import mode `{other}`")),
+        }
+    }
+}
+
+impl Display for ImportMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportMode::Merge => write!(f, "merge"),
+            ImportMode::Replace => write!(f, "replace"),
+        }
+    }
+}
+
+struct Upload {
+    file_name: String,
+    config: Config,
+    // only sequences take one
+    mode: Option<ImportMode>,
+}
+
+fn download(file_name: &str, body: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(r#"attachment; filename="{file_name}""#),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+fn refused(appstate: &AppState, status: StatusCode, error: String) -> Response {
+    (status, log_error(appstate, error)).into_response()
+}
+
+// the name is checked before anything echoes it, it comes
+// from a plain link and goes into the log and a header
+fn export_profile(input: ExportQuery) -> Result<String, String> {
+    let profile = input.profile.unwrap_or_else(active_profile);
+
+    match check_profile(&profile) {
+        Ok(_) => Ok(profile),
+        Err(_) => Err("failed to export, not a valid profile name".to_string()),
+    }
+}
+
+// the active profile comes from memory, others from their file
+pub async fn export_actions(
+    State(appstate): State<AppState>,
+    Query(input): Query<ExportQuery>,
+) -> Response {
+    let active = active_profile();
+    let profile = match export_profile(input) {
+        Ok(profile) => profile,
+        Err(e) => return refused(&appstate, StatusCode::BAD_REQUEST, e),
+    };
+
+    let actions = match profile == active {
+        true => appstate.actions.lock().unwrap().clone(),
+        false => match read_profile(&profile) {
+            Ok(config) => config.actions,
+            Err(e) => {
+                return refused(
+                    &appstate,
+                    StatusCode::NOT_FOUND,
+                    format!("failed to export profile {profile}: {e}"),
+                );
+            }
+        },
+    };
+
+    let sequence = Sequence {
+        version: CONFIG_VERSION,
+        actions: &actions,
+    };
+    match serde_json::to_string_pretty(&sequence) {
+        Ok(body) => download(&format!("pipin-{profile}-actions.json"), body),
+        Err(e) => refused(
+            &appstate,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to export actions: {e}"),
+        ),
+    }
+}
+
+pub async fn export_config(
+    State(appstate): State<AppState>,
+    Query(input): Query<ExportQuery>,
+) -> Response {
+    let active = active_profile();
+    let profile = match export_profile(input) {
+        Ok(profile) => profile,
+        Err(e) => return refused(&appstate, StatusCode::BAD_REQUEST, e),
+    };
+
+    // nothing here writes, not even an upgrade
+    let config = match profile == active {
+        true => Ok(current_config(&appstate)),
+        false => read_profile(&profile),
+    };
+    let body = config.and_then(|config| {
+        serde_json::to_string_pretty(&config).map_err(Into::into)
+    });
+
+    match body {
+        Ok(body) => download(&format!("pipin-{profile}.json"), body),
+        Err(e) => refused(
+            &appstate,
+            StatusCode::NOT_FOUND,
+            format!("failed to export profile {profile}: {e}"),
+        ),
+    }
+}
+
+// a `file` and an optional `mode`, the file's
+// extension picks json, toml or yaml
+async fn read_upload(mut multipart: Multipart) -> Result<Upload, String> {
+    let mut file = None;
+    let mut mode = None;
+
+    while let Some(field) =
+        multipart.next_field().await.map_err(|e| e.to_string())?
+    {
+        match field.name() {
+            Some("file") => {
+                // it ends up in the log panel
+                let file_name =
+                    escape_html(field.file_name().unwrap_or("upload.json"));
+                let text = field.text().await.map_err(|e| e.to_string())?;
+                file = Some((file_name, text));
+            }
+            Some("mode") => {
+                let text = field.text().await.map_err(|e| e.to_string())?;
+                mode = Some(text.parse()?);
+            }
+            _ => {}
+        }
+    }
+
+    let Some((file_name, text)) = file.filter(|(_, text)| !text.is_empty())
+    else {
+        return Err("no file uploaded".to_string());
+    };
+
+    let config = parse_upload(&file_name, &text)
+        .map_err(|e| format!("{file_name} isn't a pipin config: {e}"))?;
+
+    Ok(Upload {
+        file_name,
+        config,
+        mode,
+    })
+}
+
+pub async fn import_actions(
+    State(appstate): State<AppState>,
+    multipart: Multipart,
+) -> Response {
+    let upload = match read_upload(multipart).await {
+        Ok(upload) => upload,
+        Err(e) => {
+            return refused(
+                &appstate,
+                StatusCode::BAD_REQUEST,
+                format!("Import failed: {e}"),
+            );
+        }
+    };
+
+    let imported = upload.config.actions;
+    if imported.is_empty() {
+        return refused(
+            &appstate,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Import failed: {} has no actions", upload.file_name),
+        );
+    }
+
+    if let Err(e) = validate_actions(&appstate, &imported) {
+        return refused(
+            &appstate,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Import of {} refused, {e}", upload.file_name),
+        );
+    }
+
+    let mode = upload.mode.unwrap_or_default();
+    let mut actions = appstate.actions.lock().unwrap();
+    if mode == ImportMode::Replace {
+        actions.clear();
+    }
+    actions.extend(imported.iter().cloned());
+
+    let clone = actions.clone();
+    drop(actions);
+
+    if let Err(e) = save_actions(&clone) {
+        let _ = log_error(&appstate, format!("Failed to save config: {e}"));
+    }
+
+    let html = log_info(
+        &appstate,
+        format!(
+            "Imported {} actions from {} ({})",
+            imported.len(),
+            upload.file_name,
+            mode
+        ),
+    );
+    ([("HX-Trigger", "actions-changed")], html).into_response()
+}
+
+// replaces the active profile, the file it replaces is
+// kept as the newest backup like any other save
+pub async fn import_config(
+    State(appstate): State<AppState>,
+    multipart: Multipart,
+) -> Response {
+    let upload = match read_upload(multipart).await {
+        Ok(upload) => upload,
+        Err(e) => {
+            return refused(
+                &appstate,
+                StatusCode::BAD_REQUEST,
+                format!("Import failed: {e}"),
+            );
+        }
+    };
+
+    // a whole profile has nothing to merge into
+    if let Some(mode) = upload.mode {
+        return refused(
+            &appstate,
+            StatusCode::BAD_REQUEST,
+            format!("Import failed: a config always replaces, not {mode}"),
+        );
+    }
+
+    let mut config = upload.config;
+    if let Err(e) = validate_config(&appstate, &config) {
+        return refused(
+            &appstate,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Import of {} refused, {e}", upload.file_name),
+        );
+    }
+
+    config.version = CONFIG_VERSION;
+    if let Err(e) = save_conf(&config) {
+        return refused(
+            &appstate,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to save config: {e}"),
+        );
+    }
+    apply_config(&appstate, config);
+
+    let html = log_info(
+        &appstate,
+        format!(
+            "Imported {} as profile {}, timing changes need a restart",
+            upload.file_name,
+            active_profile()
+        ),
+    );
+    ([("HX-Trigger", "pins-changed, actions-changed")], html).into_response()
+}
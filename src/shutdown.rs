@@ -0,0 +1,256 @@
+use crate::{
+    config::save_shutdown,
+    errors::GpioError,
+    gpio::{Gpio, PinFunction, PullType},
+    logger::{log_error, log_info},
+    names::deserialize_pin,
+    servo::cancel_moves,
+    AppState,
+};
+
+use axum::{extract::State, response::Html, Form};
+use serde::{Deserialize, Serialize};
+use std::{
+    panic,
+    sync::{atomic::Ordering, Mutex, MutexGuard, TryLockError},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::sleep,
+};
+
+// how long a panicking thread waits on the gpio lock
+// before giving up, it might be the one holding it
+const PANIC_LOCK_WAIT: Duration = Duration::from_millis(200);
+
+// how long open connections get to wind down after a signal
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownAction {
+    Leave,
+    Low,
+    High,
+    Float,
+}
+
+impl std::fmt::Display for ShutdownAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShutdownAction::Leave => write!(f, "leave"),
+            ShutdownAction::Low => write!(f, "drive low"),
+            ShutdownAction::High => write!(f, "drive high"),
+            ShutdownAction::Float => write!(f, "float"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShutdownPolicy {
+    pub pin: i32,
+    pub action: ShutdownAction,
+}
+
+// levels here are physical, the point is a known
+// voltage on the wire whatever the pin's polarity
+fn apply_pin(
+    gpio: &mut Gpio,
+    policy: &ShutdownPolicy,
+) -> Result<(), GpioError> {
+    let pin = policy.pin;
+    if !(0..=27).contains(&pin) {
+        return Err(GpioError::InvalidPin(pin));
+    }
+
+    match policy.action {
+        ShutdownAction::Leave => Ok(()),
+        // latch first so the pin doesn't glitch on its way to output
+        ShutdownAction::Low => {
+            gpio.write_physical_levels(0, 1 << pin)?;
+            gpio.set_function(pin, PinFunction::Output)
+        }
+        ShutdownAction::High => {
+            gpio.write_physical_levels(1 << pin, 0)?;
+            gpio.set_function(pin, PinFunction::Output)
+        }
+        ShutdownAction::Float => {
+            gpio.set_function(pin, PinFunction::Input)?;
+            gpio.set_pull_type(pin, PullType::None)
+        }
+    }
+}
+
+// nothing to do before setup, the pins were never touched
+pub fn apply_shutdown(
+    gpio: &mut Gpio,
+    policies: &[ShutdownPolicy],
+) -> Vec<String> {
+    if !gpio.initialized {
+        return Vec::new();
+    }
+
+    policies
+        .iter()
+        .filter_map(|policy| {
+            apply_pin(gpio, policy)
+                .err()
+                .map(|e| format!("GPIO {}: {}", policy.pin, e))
+        })
+        .collect()
+}
+
+// stops everything that could drive a pin again, then applies
+// the policy. used on signals and by the terminate button
+pub fn safe_state(appstate: &AppState) -> Vec<String> {
+    appstate.stop_it.store(true, Ordering::Relaxed);
+    cancel_moves(appstate);
+    appstate.pwm.stop_all();
+
+    let policies = appstate.shutdown.lock().unwrap().clone();
+    let mut hwpwm = appstate.hwpwm.lock().unwrap();
+    let mut gpio = appstate.gpio.lock().unwrap();
+    hwpwm.stop_all(&mut gpio);
+
+    apply_shutdown(&mut gpio, &policies)
+}
+
+fn try_lock_for<T>(
+    mutex: &Mutex<T>,
+    wait: Duration,
+) -> Option<MutexGuard<'_, T>> {
+    let start = Instant::now();
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) if start.elapsed() < wait => {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    }
+}
+
+// the hook still runs with panic = "abort", it's the
+// unwinding that's skipped. nothing here can block for
+// good since the panicking thread may hold the gpio lock
+pub fn install_panic_hook(appstate: &AppState) {
+    let gpio = appstate.gpio.clone();
+    let shutdown = appstate.shutdown.clone();
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        let policies = match try_lock_for(&shutdown, PANIC_LOCK_WAIT) {
+            Some(policies) => policies.clone(),
+            None => return,
+        };
+
+        match try_lock_for(&gpio, PANIC_LOCK_WAIT) {
+            Some(mut gpio) => {
+                for e in apply_shutdown(&mut gpio, &policies) {
+                    eprintln!("safe state failed for {e}");
+                }
+            }
+            None => eprintln!("gpio busy, pins left as they are"),
+        }
+    }));
+}
+
+// resolves on SIGINT, SIGTERM or SIGHUP
+pub async fn wait_for_signal() -> &'static str {
+    let mut term =
+        signal(SignalKind::terminate()).expect("failed to get SIGTERM handle");
+    let mut hangup =
+        signal(SignalKind::hangup()).expect("failed to get SIGHUP handle");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = term.recv() => "SIGTERM",
+        _ = hangup.recv() => "SIGHUP",
+    }
+}
+
+// the graceful part of a signal. nothing may drive a pin once
+// the safe states are on, so requests get refused and the
+// websockets closed before they're applied
+pub async fn shut_down(appstate: &AppState) {
+    let signal = wait_for_signal().await;
+    println!("got {signal}, applying shutdown states");
+
+    appstate.shutting_down.send_replace(true);
+    for poll in appstate.sensors.polls() {
+        appstate.sensors.stop(&poll.sensor);
+    }
+
+    for e in safe_state(appstate) {
+        println!("safe state failed for {e}");
+    }
+}
+
+// resolves once shut_down has started, for the
+// long lived tasks that have to let go
+pub async fn until_shutdown(appstate: &AppState) {
+    let mut shutting_down = appstate.shutting_down.subscribe();
+    let _ = shutting_down.wait_for(|closing| *closing).await;
+}
+
+// the pins are already safe by the time this starts, so a
+// connection that won't close or a second signal just exits
+pub async fn grace_period(appstate: &AppState) {
+    until_shutdown(appstate).await;
+
+    tokio::select! {
+        _ = sleep(SHUTDOWN_GRACE) => println!(
+            "connections still open after {}s, exiting",
+            SHUTDOWN_GRACE.as_secs()
+        ),
+        signal = wait_for_signal() => println!("got {signal} again, exiting"),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShutdownForm {
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub pin: i32,
+    pub action: ShutdownAction,
+}
+
+fn save(appstate: &AppState) {
+    let policies = appstate.shutdown.lock().unwrap().clone();
+    if let Err(e) = save_shutdown(&policies) {
+        let _ = log_error(appstate, format!("Failed to save config: {}", e));
+    }
+}
+
+// leave is the default, so it just drops the pin's entry
+pub async fn set_shutdown(
+    State(appstate): State<AppState>,
+    Form(input): Form<ShutdownForm>,
+) -> Html<String> {
+    if !(0..=27).contains(&input.pin) {
+        return log_error(&appstate, GpioError::InvalidPin(input.pin));
+    }
+
+    {
+        let mut policies = appstate.shutdown.lock().unwrap();
+        policies.retain(|policy| policy.pin != input.pin);
+        if input.action != ShutdownAction::Leave {
+            policies.push(ShutdownPolicy {
+                pin: input.pin,
+                action: input.action,
+            });
+            policies.sort_by_key(|policy| policy.pin);
+        }
+    }
+    save(&appstate);
+
+    log_info(
+        &appstate,
+        format!("GPIO {} on shutdown: {}", input.pin, input.action),
+    )
+}
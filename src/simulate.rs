@@ -0,0 +1,584 @@
+use crate::{
+    actions::Action,
+    bitbang::{shift_out_duration, DEFAULT_CLOCK_US},
+    gpio::{PinLevel, PullType},
+    logger::{escape_html, log_error, log_info},
+    names::resolve,
+    AppState,
+};
+
+use axum::{extract::State, response::Html, Form};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// how many times a looping sequence gets
+// unrolled when nobody asks for a count
+const DEFAULT_LOOP_PASSES: u32 = 3;
+const MAX_LOOP_PASSES: u32 = 100;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SimulateForm {
+    pub should_loop: Option<String>,
+    // stand-in levels for inputs, e.g. "17=high, 22=low"
+    pub inputs: Option<String>,
+    pub passes: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LevelChange {
+    pub time_us: u64,
+    pub pin: i32,
+    pub level: PinLevel,
+    pub step: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Simulation {
+    pub timeline: Vec<LevelChange>,
+    pub total_us: u64,
+    pub passes: u32,
+    pub warnings: Vec<String>,
+}
+
+// what the sim knows about a pin
+// driven takes priority over the stand-in
+// and the stand-in over the pull
+#[derive(Default)]
+struct SimPin {
+    driven: Option<PinLevel>,
+    pull: Option<PullType>,
+}
+
+// walks through the actions without touching Gpio
+// waits are resolved against the stand-in inputs,
+// or whatever the sequence itself drove the pin to
+pub fn simulate(
+    actions: &[Action],
+    inputs: &HashMap<i32, PinLevel>,
+    should_loop: bool,
+    passes: u32,
+    debounce: &HashMap<i32, u32>,
+) -> Simulation {
+    let mut sim = Simulation::default();
+    let mut pins: HashMap<i32, SimPin> = HashMap::new();
+    let mut time_us: u64 = 0;
+
+    if actions.is_empty() {
+        sim.warnings.push("Sequence is empty".to_string());
+        return sim;
+    }
+
+    let passes = if should_loop {
+        passes.clamp(1, MAX_LOOP_PASSES)
+    } else {
+        1
+    };
+
+    'passes: for pass in 0..passes {
+        let pass_start = time_us;
+
+        for (step, action) in actions.iter().enumerate() {
+            match action {
+                Action::SetHigh(pin) => drive(
+                    &mut sim,
+                    &mut pins,
+                    time_us,
+                    *pin,
+                    PinLevel::High,
+                    step,
+                ),
+                Action::SetLow(pin) => drive(
+                    &mut sim,
+                    &mut pins,
+                    time_us,
+                    *pin,
+                    PinLevel::Low,
+                    step,
+                ),
+                Action::Delay(time) => {
+                    time_us += (*time).max(0) as u64 * 1000;
+                }
+                Action::DelayUs(time) => {
+                    time_us += (*time).max(0) as u64;
+                }
+                Action::Pulse(pin, width, level) => {
+                    drive(&mut sim, &mut pins, time_us, *pin, *level, step);
+                    time_us += (*width).max(0) as u64;
+                    let idle = level.inverted();
+                    drive(&mut sim, &mut pins, time_us, *pin, idle, step);
+                }
+                Action::Toggle(pin) => {
+                    let level = read_level(&pins, inputs, *pin).inverted();
+                    drive(&mut sim, &mut pins, time_us, *pin, level, step);
+                }
+                Action::SetPwm(pin, freq, duty)
+                | Action::SetHwPwm(pin, freq, duty) => {
+                    // pwm is free running so there's no
+                    // single level to put on the timeline
+                    if pass == 0 {
+                        sim.warnings.push(format!(
+                            "Step {}: GPIO {} PWM {}Hz {}% isn't expanded \
+                             in the timeline",
+                            step + 1,
+                            pin,
+                            freq,
+                            duty
+                        ));
+                    }
+                    pins.entry(*pin).or_default().driven = None;
+                }
+                // bus traffic doesn't touch the timeline
+                Action::ServoAngle(_, _)
+                | Action::I2cWrite(_, _, _)
+                | Action::I2cRead(_, _, _)
+                | Action::SpiTransfer(_, _)
+                | Action::UartSend(_)
+                | Action::UartExpect(_, _)
+                | Action::ReadSensor(_) => {}
+                Action::ShiftOut(data, _, _, bytes, _) => {
+                    if pass == 0 {
+                        sim.warnings.push(format!(
+                            "Step {}: GPIO {} shift out isn't expanded \
+                             in the timeline",
+                            step + 1,
+                            data
+                        ));
+                    }
+                    time_us += shift_out_duration(bytes.len(), DEFAULT_CLOCK_US)
+                        .as_micros() as u64;
+                }
+                Action::ServoMove(_, _, time) => {
+                    time_us += *time as u64 * 1000;
+                }
+                // no way to know what the sensor will read
+                Action::WaitForSensor(_, _, _, _) => {
+                    if pass == 0 {
+                        sim.warnings.push(format!(
+                            "Step {}: {} is assumed to pass straight away",
+                            step + 1,
+                            action.display_text()
+                        ));
+                    }
+                }
+                Action::WaitForHigh(pin) | Action::WaitForLow(pin) => {
+                    let wanted = match action {
+                        Action::WaitForHigh(_) => PinLevel::High,
+                        _ => PinLevel::Low,
+                    };
+                    let level = read_level(&pins, inputs, *pin);
+
+                    if level != wanted {
+                        sim.warnings.push(format!(
+                            "Step {}: GPIO {} stays {}, {} would wait forever",
+                            step + 1,
+                            pin,
+                            level,
+                            action.display_text()
+                        ));
+                        unreachable_after(&mut sim, actions, step, pass);
+                        break 'passes;
+                    }
+
+                    // a debounced pin has to hold for the settle time
+                    if let Some(settle_ms) = debounce.get(pin) {
+                        time_us += *settle_ms as u64 * 1000;
+                    }
+                }
+                Action::SetPullUp(pin) => {
+                    pins.entry(*pin).or_default().pull = Some(PullType::Up)
+                }
+                Action::SetPullDown(pin) => {
+                    pins.entry(*pin).or_default().pull = Some(PullType::Down)
+                }
+            }
+        }
+
+        if should_loop && pass == 0 && time_us == pass_start {
+            sim.warnings.push(
+                "Loop takes no time per pass and will spin the cpu, \
+                 add a delay"
+                    .to_string(),
+            );
+        }
+
+        sim.passes = pass + 1;
+    }
+
+    if should_loop && sim.passes == passes {
+        sim.warnings.push(format!(
+            "Loop never ends on its own, \
+             only the first {} passes are shown",
+            sim.passes
+        ));
+    }
+
+    sim.total_us = time_us;
+    sim
+}
+
+fn drive(
+    sim: &mut Simulation,
+    pins: &mut HashMap<i32, SimPin>,
+    time_us: u64,
+    pin: i32,
+    level: PinLevel,
+    step: usize,
+) {
+    let state = pins.entry(pin).or_default();
+
+    // only record actual changes
+    if state.driven != Some(level) {
+        sim.timeline.push(LevelChange {
+            time_us,
+            pin,
+            level,
+            step,
+        });
+    }
+
+    state.driven = Some(level);
+}
+
+fn read_level(
+    pins: &HashMap<i32, SimPin>,
+    inputs: &HashMap<i32, PinLevel>,
+    pin: i32,
+) -> PinLevel {
+    let state = pins.get(&pin);
+
+    if let Some(level) = state.and_then(|s| s.driven) {
+        return level;
+    }
+
+    if let Some(level) = inputs.get(&pin) {
+        return *level;
+    }
+
+    // nothing connected, so it floats
+    // to wherever the pull puts it
+    match state.and_then(|s| s.pull) {
+        Some(PullType::Up) => PinLevel::High,
+        _ => PinLevel::Low,
+    }
+}
+
+fn unreachable_after(
+    sim: &mut Simulation,
+    actions: &[Action],
+    step: usize,
+    pass: u32,
+) {
+    // steps before the stuck wait already
+    // ran once, so only the first pass
+    // can leave anything unreachable
+    if pass > 0 || step + 1 >= actions.len() {
+        return;
+    }
+
+    let steps: Vec<String> = (step + 1..actions.len())
+        .map(|i| (i + 1).to_string())
+        .collect();
+
+    sim.warnings
+        .push(format!("Unreachable steps: {}", steps.join(", ")));
+}
+
+// "17=high, door=low" -> {17: High, 22: Low}. errors echo
+// the form, escaped here since they go to the log panel
+pub fn parse_inputs(raw: &str) -> Result<HashMap<i32, PinLevel>, String> {
+    let mut inputs = HashMap::new();
+
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (pin, level) = entry.split_once('=').ok_or_else(|| {
+            format!("Expected pin=level, got `{}`", escape_html(entry))
+        })?;
+
+        let pin = resolve(pin)?;
+
+        inputs.insert(pin, level.parse::<PinLevel>()?);
+    }
+
+    Ok(inputs)
+}
+
+fn format_time(time_us: u64) -> String {
+    format!("{}.{:03}ms", time_us / 1000, time_us % 1000)
+}
+
+impl Simulation {
+    pub fn to_html(&self) -> String {
+        let mut html = format!(
+            r#"<div class="sim-summary">Total: {} over {} pass(es), {} change(s)</div>"#,
+            format_time(self.total_us),
+            self.passes,
+            self.timeline.len()
+        );
+
+        for warning in &self.warnings {
+            html.push_str(&format!(
+                r#"<div class="sim-warning">{}</div>"#,
+                warning
+            ));
+        }
+
+        for change in &self.timeline {
+            html.push_str(&format!(
+                r#"<div class="sim-row">
+                <span class="log-time">{}</span>
+                <span>GPIO {} -> {}</span>
+                <span>step {}</span>
+            </div>"#,
+                format_time(change.time_us),
+                change.pin,
+                change.level,
+                change.step + 1
+            ));
+        }
+
+        html
+    }
+}
+
+pub async fn simulate_actions(
+    State(appstate): State<AppState>,
+    Form(input): Form<SimulateForm>,
+) -> Html<String> {
+    let should_loop = input.should_loop.as_deref() == Some("true");
+    let inputs = match parse_inputs(input.inputs.as_deref().unwrap_or("")) {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            return log_error(&appstate, format!("Simulation inputs: {e}"));
+        }
+    };
+
+    let actions = appstate.actions.lock().unwrap().clone();
+    let debounce = appstate.debounce.lock().unwrap().clone();
+    let sim = simulate(
+        &actions,
+        &inputs,
+        should_loop,
+        input.passes.unwrap_or(DEFAULT_LOOP_PASSES),
+        &debounce,
+    );
+
+    let _ = log_info(
+        &appstate,
+        format!(
+            "Simulated sequence: {} with {} warning(s)",
+            format_time(sim.total_us),
+            sim.warnings.len()
+        ),
+    );
+
+    Html(sim.to_html())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PinLevel::{High, Low};
+
+    fn run(actions: &[Action], inputs: &[(i32, PinLevel)]) -> Simulation {
+        let inputs = inputs.iter().copied().collect();
+        simulate(actions, &inputs, false, 1, &HashMap::new())
+    }
+
+    fn run_loop(actions: &[Action], passes: u32) -> Simulation {
+        simulate(actions, &HashMap::new(), true, passes, &HashMap::new())
+    }
+
+    // (time_us, pin, level, step) for each change
+    fn changes(sim: &Simulation) -> Vec<(u64, i32, PinLevel, usize)> {
+        sim.timeline
+            .iter()
+            .map(|c| (c.time_us, c.pin, c.level, c.step))
+            .collect()
+    }
+
+    fn warned(sim: &Simulation, text: &str) -> bool {
+        sim.warnings.iter().any(|warning| warning.contains(text))
+    }
+
+    #[test]
+    fn timeline_only_records_changes() {
+        let sim = run(
+            &[
+                Action::SetHigh(17),
+                Action::Delay(5),
+                Action::SetLow(17),
+                Action::DelayUs(250),
+                Action::SetLow(17),
+            ],
+            &[],
+        );
+
+        assert_eq!(changes(&sim), vec![(0, 17, High, 0), (5000, 17, Low, 2)]);
+        assert_eq!(sim.total_us, 5250);
+        assert_eq!(sim.passes, 1);
+        assert!(sim.warnings.is_empty(), "{:?}", sim.warnings);
+    }
+
+    #[test]
+    fn pulse_goes_back_to_idle_after_its_width() {
+        let sim = run(&[Action::Pulse(4, 100, High)], &[]);
+
+        assert_eq!(changes(&sim), vec![(0, 4, High, 0), (100, 4, Low, 0)]);
+        assert_eq!(sim.total_us, 100);
+    }
+
+    #[test]
+    fn toggle_starts_from_the_stand_in_input() {
+        let sim = run(&[Action::Toggle(17), Action::Toggle(17)], &[(17, High)]);
+
+        assert_eq!(changes(&sim), vec![(0, 17, Low, 0), (0, 17, High, 1)]);
+    }
+
+    #[test]
+    fn empty_sequence_warns() {
+        let sim = run(&[], &[]);
+
+        assert!(sim.timeline.is_empty());
+        assert!(warned(&sim, "Sequence is empty"));
+    }
+
+    #[test]
+    fn loop_is_unrolled_for_each_pass() {
+        let sim = run_loop(
+            &[
+                Action::SetHigh(17),
+                Action::Delay(1),
+                Action::SetLow(17),
+                Action::Delay(1),
+            ],
+            2,
+        );
+
+        assert_eq!(
+            changes(&sim),
+            vec![
+                (0, 17, High, 0),
+                (1000, 17, Low, 2),
+                (2000, 17, High, 0),
+                (3000, 17, Low, 2),
+            ]
+        );
+        assert_eq!(sim.total_us, 4000);
+        assert_eq!(sim.passes, 2);
+        assert!(warned(&sim, "only the first 2 passes are shown"));
+    }
+
+    #[test]
+    fn loop_passes_are_clamped() {
+        let actions = [Action::Delay(1)];
+
+        assert_eq!(run_loop(&actions, 0).passes, 1);
+        assert_eq!(run_loop(&actions, 1000).passes, MAX_LOOP_PASSES);
+    }
+
+    #[test]
+    fn loop_without_a_delay_warns_about_spinning() {
+        let sim = run_loop(&[Action::Toggle(17)], 3);
+
+        assert!(warned(&sim, "will spin the cpu"));
+        assert_eq!(sim.total_us, 0);
+    }
+
+    #[test]
+    fn wait_with_no_input_stops_the_simulation() {
+        let sim = run(
+            &[
+                Action::SetHigh(17),
+                Action::WaitForHigh(22),
+                Action::SetLow(17),
+                Action::Delay(1),
+            ],
+            &[],
+        );
+
+        // nothing after the wait makes it onto the timeline
+        assert_eq!(changes(&sim), vec![(0, 17, High, 0)]);
+        assert_eq!(sim.total_us, 0);
+        assert!(warned(&sim, "GPIO 22 stays LOW"));
+        assert!(warned(&sim, "Unreachable steps: 3, 4"));
+    }
+
+    #[test]
+    fn wait_for_low_on_a_pulled_up_pin_never_ends() {
+        let sim = run(&[Action::SetPullUp(5), Action::WaitForLow(5)], &[]);
+
+        assert!(warned(&sim, "GPIO 5 stays HIGH"));
+        // the wait is the last step, nothing to mark
+        assert!(!warned(&sim, "Unreachable"));
+    }
+
+    #[test]
+    fn wait_passes_on_a_matching_input() {
+        let sim = run(
+            &[Action::WaitForHigh(22), Action::SetHigh(17)],
+            &[(22, High)],
+        );
+
+        assert_eq!(changes(&sim), vec![(0, 17, High, 1)]);
+        assert!(sim.warnings.is_empty(), "{:?}", sim.warnings);
+    }
+
+    #[test]
+    fn wait_on_a_driven_pin_uses_the_driven_level() {
+        let sim =
+            run(&[Action::SetLow(17), Action::WaitForLow(17)], &[(17, High)]);
+
+        assert!(sim.warnings.is_empty(), "{:?}", sim.warnings);
+    }
+
+    #[test]
+    fn debounced_wait_takes_the_settle_time() {
+        let inputs = HashMap::from([(22, High)]);
+        let debounce = HashMap::from([(22, 20)]);
+        let sim = simulate(
+            &[Action::WaitForHigh(22), Action::SetHigh(17)],
+            &inputs,
+            false,
+            1,
+            &debounce,
+        );
+
+        assert_eq!(changes(&sim), vec![(20_000, 17, High, 1)]);
+    }
+
+    #[test]
+    fn wait_stuck_in_a_later_pass_has_nothing_unreachable() {
+        // the first pass toggles 17 high so the wait passes,
+        // the second toggles it back low and gets stuck
+        let sim = run_loop(
+            &[
+                Action::Toggle(17),
+                Action::WaitForHigh(17),
+                Action::Delay(1),
+            ],
+            5,
+        );
+
+        assert_eq!(sim.passes, 1);
+        assert_eq!(sim.total_us, 1000);
+        assert!(warned(&sim, "GPIO 17 stays LOW"));
+        assert!(!warned(&sim, "Unreachable"));
+        assert!(!warned(&sim, "Loop never ends"));
+    }
+
+    #[test]
+    fn inputs_parse_from_the_form() {
+        let inputs = parse_inputs("17=high, 22=0,").unwrap();
+
+        assert_eq!(inputs, HashMap::from([(17, High), (22, Low)]));
+        assert!(parse_inputs("17").is_err());
+        assert!(parse_inputs("17=maybe").is_err());
+    }
+
+    #[test]
+    fn input_errors_are_escaped() {
+        for raw in ["<b>", "<b>=high", "17=<b>"] {
+            let e = parse_inputs(raw).unwrap_err();
+            assert!(e.contains("&lt;b&gt;") && !e.contains('<'), "{e}");
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{
+    errors::GpioError,
+    hex::{parse_bytes, to_hex},
+    logger::{log_error, log_info},
+    AppState,
+};
+
+use axum::{extract::State, Json};
+use nix::libc::{c_ulong, ioctl};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    os::fd::AsRawFd,
+};
+
+// SPI0 on GPIO 7-11, CE0 is GPIO 8
+pub const DEFAULT_SPI_BUS: u8 = 0;
+
+// _IOW('k', nr, size) from linux/spi/spidev.h
+const fn spi_iow(nr: c_ulong, size: usize) -> c_ulong {
+    (1 << 30) | ((size as c_ulong) << 16) | ((b'k' as c_ulong) << 8) | nr
+}
+
+const SPI_IOC_WR_MODE: c_ulong = spi_iow(1, 1);
+const SPI_IOC_WR_BITS_PER_WORD: c_ulong = spi_iow(3, 1);
+const SPI_IOC_WR_MAX_SPEED_HZ: c_ulong = spi_iow(4, 4);
+const SPI_IOC_MESSAGE_1: c_ulong = spi_iow(0, size_of::<SpiIocTransfer>());
+
+// the driver limits a single transfer to its buffer size
+const MAX_TRANSFER: usize = 4096;
+
+#[repr(C)]
+#[derive(Default)]
+struct SpiIocTransfer {
+    tx_buf: u64,
+    rx_buf: u64,
+    len: u32,
+    speed_hz: u32,
+    delay_usecs: u16,
+    bits_per_word: u8,
+    cs_change: u8,
+    tx_nbits: u8,
+    rx_nbits: u8,
+    word_delay_usecs: u8,
+    pad: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpiConfig {
+    // CPOL << 1 | CPHA
+    #[serde(default)]
+    pub mode: u8,
+    #[serde(default = "default_speed")]
+    pub speed_hz: u32,
+    #[serde(default = "default_bits")]
+    pub bits_per_word: u8,
+}
+
+fn default_speed() -> u32 {
+    500_000
+}
+
+fn default_bits() -> u8 {
+    8
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        SpiConfig {
+            mode: 0,
+            speed_hz: default_speed(),
+            bits_per_word: default_bits(),
+        }
+    }
+}
+
+// /dev/spidevB.C, needs `dtparam=spi=on`
+pub struct Spidev {
+    file: File,
+    config: SpiConfig,
+}
+
+impl Spidev {
+    pub fn open(bus: u8, cs: u8, config: SpiConfig) -> Result<Self, GpioError> {
+        if config.mode > 3 {
+            return Err(GpioError::Spi("mode must be 0-3".to_string()));
+        }
+
+        if config.speed_hz == 0 || !(1..=32).contains(&config.bits_per_word) {
+            return Err(GpioError::Spi(
+                "speed must be non zero and bits per word 1-32".to_string(),
+            ));
+        }
+
+        let path = format!("/dev/spidev{bus}.{cs}");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| GpioError::Spi(format!("{path}: {e}")))?;
+
+        let spi = Spidev { file, config };
+        spi.write_setting(SPI_IOC_WR_MODE, &spi.config.mode)?;
+        spi.write_setting(SPI_IOC_WR_BITS_PER_WORD, &spi.config.bits_per_word)?;
+        spi.write_setting(SPI_IOC_WR_MAX_SPEED_HZ, &spi.config.speed_hz)?;
+
+        Ok(spi)
+    }
+
+    fn write_setting<T>(
+        &self,
+        request: c_ulong,
+        value: &T,
+    ) -> Result<(), GpioError> {
+        let ret =
+            unsafe { ioctl(self.file.as_raw_fd(), request, value as *const T) };
+        if ret < 0 {
+            return Err(GpioError::Spi(
+                std::io::Error::last_os_error().to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // full duplex, gets back as many bytes as it sends
+    pub fn transfer(&self, tx: &[u8]) -> Result<Vec<u8>, GpioError> {
+        if tx.is_empty() || tx.len() > MAX_TRANSFER {
+            return Err(GpioError::Spi(format!(
+                "transfers must be 1-{MAX_TRANSFER} bytes"
+            )));
+        }
+
+        let mut rx = vec![0u8; tx.len()];
+        let xfer = SpiIocTransfer {
+            tx_buf: tx.as_ptr() as u64,
+            rx_buf: rx.as_mut_ptr() as u64,
+            len: tx.len() as u32,
+            speed_hz: self.config.speed_hz,
+            bits_per_word: self.config.bits_per_word,
+            ..Default::default()
+        };
+
+        let ret =
+            unsafe { ioctl(self.file.as_raw_fd(), SPI_IOC_MESSAGE_1, &xfer) };
+        if ret < 0 {
+            return Err(GpioError::Spi(
+                std::io::Error::last_os_error().to_string(),
+            ));
+        }
+
+        Ok(rx)
+    }
+}
+
+// data is hex, same as the i2c forms
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpiTransferRequest {
+    #[serde(default)]
+    pub bus: u8,
+    #[serde(default)]
+    pub cs: u8,
+    #[serde(flatten)]
+    pub config: SpiConfig,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpiTransferResponse {
+    pub ok: bool,
+    pub rx: Option<String>,
+    pub error: Option<String>,
+}
+
+pub fn spi_transfer_hex(
+    bus: u8,
+    cs: u8,
+    config: SpiConfig,
+    data: &str,
+) -> Result<Vec<u8>, GpioError> {
+    let tx = parse_bytes(data).map_err(GpioError::Spi)?;
+    Spidev::open(bus, cs, config)?.transfer(&tx)
+}
+
+// also reachable from the websocket, the
+// reply is logged either way
+pub fn run_spi_transfer(
+    appstate: &AppState,
+    input: &SpiTransferRequest,
+) -> Result<Vec<u8>, GpioError> {
+    let result = spi_transfer_hex(
+        input.bus,
+        input.cs,
+        input.config.clone(),
+        &input.data,
+    );
+
+    match &result {
+        Ok(rx) => {
+            let _ = log_info(
+                appstate,
+                format!(
+                    "SPI {}.{} {} -> {}",
+                    input.bus,
+                    input.cs,
+                    input.data.trim(),
+                    to_hex(rx)
+                ),
+            );
+        }
+        Err(e) => {
+            let _ = log_error(appstate, format!("SPI transfer failed: {e}"));
+        }
+    }
+
+    result
+}
+
+pub async fn spi_transfer(
+    State(appstate): State<AppState>,
+    Json(input): Json<SpiTransferRequest>,
+) -> Json<SpiTransferResponse> {
+    Json(match run_spi_transfer(&appstate, &input) {
+        Ok(rx) => SpiTransferResponse {
+            ok: true,
+            rx: Some(to_hex(&rx)),
+            error: None,
+        },
+        Err(e) => SpiTransferResponse {
+            ok: false,
+            rx: None,
+            error: Some(e.to_string()),
+        },
+    })
+}
+
+// whatever the overlays have enabled
+pub async fn spi_devices() -> Json<Vec<String>> {
+    let mut devices: Vec<String> = std::fs::read_dir("/dev")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| name.starts_with("spidev"))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    devices.sort();
+    Json(devices)
+}
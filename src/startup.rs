@@ -0,0 +1,84 @@
+use crate::{
+    errors::GpioError,
+    gpio::{Gpio, PinDirection, PinFunction, PinLevel, PullType},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PinStartup {
+    // a number, names aren't known yet while the config loads
+    pub pin: i32,
+    // Input and Output cover the direction,
+    // Alt0-5 hand the pin to a peripheral
+    #[serde(default = "default_function")]
+    pub function: PinFunction,
+    // logical, only used for outputs
+    #[serde(default)]
+    pub level: Option<PinLevel>,
+    #[serde(default)]
+    pub pull: Option<PullType>,
+}
+
+fn default_function() -> PinFunction {
+    PinFunction::Input
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StartupConfig {
+    // run setup on boot instead of waiting for the button
+    #[serde(default)]
+    pub auto_setup: bool,
+    #[serde(default)]
+    pub pins: Vec<PinStartup>,
+}
+
+impl StartupConfig {
+    pub fn validate(&self) -> Result<(), GpioError> {
+        match self.pins.iter().find(|p| !(0..=27).contains(&p.pin)) {
+            Some(state) => Err(GpioError::InvalidPin(state.pin)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn apply_pin(gpio: &mut Gpio, state: &PinStartup) -> Result<(), GpioError> {
+    if !(0..=27).contains(&state.pin) {
+        return Err(GpioError::InvalidPin(state.pin));
+    }
+
+    // pull first so an input never floats in between
+    if let Some(pull) = state.pull {
+        gpio.set_pull_type(state.pin, pull)?;
+    }
+
+    match state.function {
+        PinFunction::Input => {
+            gpio.set_direction(state.pin, PinDirection::Input)
+        }
+        PinFunction::Output => {
+            let level = state.level.unwrap_or(PinLevel::Low);
+
+            // load the output latch before the pin becomes
+            // an output so it doesn't glitch to the old level
+            match level {
+                PinLevel::High => gpio.write_levels(1 << state.pin, 0)?,
+                PinLevel::Low => gpio.write_levels(0, 1 << state.pin)?,
+            }
+            gpio.set_level(state.pin, level)
+        }
+        function => gpio.set_function(state.pin, function),
+    }
+}
+
+// keeps going past a bad pin so one typo doesn't
+// leave the rest of the rig in whatever state it was
+pub fn apply_startup(gpio: &mut Gpio, pins: &[PinStartup]) -> Vec<String> {
+    pins.iter()
+        .filter_map(|state| {
+            apply_pin(gpio, state)
+                .err()
+                .map(|e| format!("GPIO {}: {}", state.pin, e))
+        })
+        .collect()
+}
@@ -0,0 +1,197 @@
+use nix::{
+    libc::{sched_param, sched_setscheduler, SCHED_FIFO},
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    hint::spin_loop,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+// anything shorter than this goes through
+// the runner thread instead of tokio's timer
+pub const PRECISE_DELAY_MAX_US: u64 = 20_000;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TimingConfig {
+    // SCHED_FIFO needs root or CAP_SYS_NICE
+    pub realtime: bool,
+    pub priority: i32,
+    pub cpu: Option<usize>,
+    // how long before the deadline we stop
+    // sleeping and start spinning
+    pub spin_us: u64,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            realtime: false,
+            priority: 50,
+            cpu: None,
+            spin_us: 200,
+        }
+    }
+}
+
+// dedicated thread for timing sensitive work
+// so we're not at the mercy of the tokio scheduler
+#[derive(Clone)]
+pub struct RtRunner {
+    tx: mpsc::Sender<Job>,
+    spin: Duration,
+}
+
+impl RtRunner {
+    pub fn spawn(config: &TimingConfig) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let thread_config = config.clone();
+
+        thread::Builder::new()
+            .name("pipin-rt".to_string())
+            .spawn(move || {
+                setup_thread(&thread_config);
+
+                while let Ok(job) = rx.recv() {
+                    job();
+                }
+            })
+            .expect("failed to spawn rt runner thread");
+
+        RtRunner {
+            tx,
+            spin: Duration::from_micros(config.spin_us),
+        }
+    }
+
+    // runs f on the rt thread and waits for the result
+    // None if the thread went away
+    pub async fn run<T, F>(&self, f: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        let job: Job = Box::new(move || {
+            let _ = reply_tx.send(f());
+        });
+
+        self.tx.send(job).ok()?;
+        reply_rx.await.ok()
+    }
+
+    // returns how long we actually slept
+    pub async fn delay(&self, duration: Duration) -> Duration {
+        let spin = self.spin;
+        let start = Instant::now();
+
+        match self.run(move || precise_sleep(duration, spin)).await {
+            Some(elapsed) => elapsed,
+            None => {
+                // runner is gone, best effort
+                tokio::time::sleep(duration).await;
+                start.elapsed()
+            }
+        }
+    }
+
+    pub fn spin(&self) -> Duration {
+        self.spin
+    }
+}
+
+fn setup_thread(config: &TimingConfig) {
+    if let Some(cpu) = config.cpu {
+        let mut cpuset = CpuSet::new();
+        let pinned = cpuset
+            .set(cpu)
+            .and_then(|_| sched_setaffinity(Pid::from_raw(0), &cpuset));
+
+        match pinned {
+            Ok(_) => println!("rt runner pinned to cpu {cpu}"),
+            Err(e) => println!("failed to pin rt runner to cpu {cpu}: {e}"),
+        }
+    }
+
+    if config.realtime {
+        let param = sched_param {
+            sched_priority: config.priority,
+        };
+
+        // 0 is the calling thread
+        let ret = unsafe { sched_setscheduler(0, SCHED_FIFO, &param) };
+        if ret == 0 {
+            println!("rt runner using SCHED_FIFO {}", config.priority);
+        } else {
+            println!(
+                "failed to set SCHED_FIFO: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+// hybrid sleep, let the kernel have us for
+// most of it then spin out the rest
+pub fn precise_sleep(duration: Duration, spin: Duration) -> Duration {
+    let start = Instant::now();
+    let deadline = start + duration;
+
+    if duration > spin {
+        thread::sleep(duration - spin);
+    }
+
+    while Instant::now() < deadline {
+        spin_loop();
+    }
+
+    start.elapsed()
+}
+
+// how far off our delays were for a single run
+#[derive(Default, Debug)]
+pub struct RunStats {
+    pub delays: u32,
+    total_jitter: Duration,
+    max_jitter: Duration,
+}
+
+impl RunStats {
+    pub fn record(&mut self, requested: Duration, actual: Duration) {
+        let jitter = actual.abs_diff(requested);
+
+        self.delays += 1;
+        self.total_jitter += jitter;
+        self.max_jitter = self.max_jitter.max(jitter);
+    }
+
+    // folds in the delays from a bit-banged transfer
+    pub fn merge(&mut self, other: RunStats) {
+        self.delays += other.delays;
+        self.total_jitter += other.total_jitter;
+        self.max_jitter = self.max_jitter.max(other.max_jitter);
+    }
+
+    pub fn summary(&self) -> Option<String> {
+        if self.delays == 0 {
+            return None;
+        }
+
+        let avg = self.total_jitter / self.delays;
+
+        Some(format!(
+            "Timing: {} delays, jitter avg {}us, max {}us",
+            self.delays,
+            avg.as_micros(),
+            self.max_jitter.as_micros()
+        ))
+    }
+}
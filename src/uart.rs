@@ -0,0 +1,543 @@
+use crate::{
+    errors::GpioError,
+    logger::{escape_html, log_error, log_info},
+    shutdown::until_shutdown,
+    AppState,
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::{Html, Response},
+    Form,
+};
+use futures::{SinkExt, StreamExt};
+use nix::sys::termios::{
+    self, BaudRate, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg,
+    SpecialCharacterIndices,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{sync::broadcast, time::timeout};
+
+// GPIO 14/15 once the login console is turned off
+const DEFAULT_UART_PATH: &str = "/dev/serial0";
+
+// unread bytes kept around for UartExpect,
+// the oldest get dropped past this
+const RX_BUFFER_MAX: usize = 64 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UartConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+    #[serde(default = "default_data_bits")]
+    pub data_bits: u8,
+    #[serde(default = "default_parity")]
+    pub parity: Parity,
+    #[serde(default = "default_stop_bits")]
+    pub stop_bits: u8,
+}
+
+fn default_path() -> String {
+    DEFAULT_UART_PATH.to_string()
+}
+
+fn default_baud() -> u32 {
+    115_200
+}
+
+fn default_data_bits() -> u8 {
+    8
+}
+
+fn default_parity() -> Parity {
+    Parity::None
+}
+
+fn default_stop_bits() -> u8 {
+    1
+}
+
+fn baud_rate(baud: u32) -> Result<BaudRate, GpioError> {
+    let rate = match baud {
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        460800 => BaudRate::B460800,
+        921600 => BaudRate::B921600,
+        _ => {
+            return Err(GpioError::Uart(format!("unsupported baud {baud}")));
+        }
+    };
+
+    Ok(rate)
+}
+
+// raw mode, no echo or line editing, and reads
+// that give up after 100ms so the reader can stop
+fn configure_port(file: &File, config: &UartConfig) -> Result<(), GpioError> {
+    let path = escape_html(&config.path);
+    let err = |e: nix::Error| GpioError::Uart(format!("{path}: {e}"));
+
+    let mut tio = termios::tcgetattr(file).map_err(err)?;
+    termios::cfmakeraw(&mut tio);
+
+    let rate = baud_rate(config.baud)?;
+    termios::cfsetspeed(&mut tio, rate).map_err(err)?;
+
+    tio.control_flags &= !(ControlFlags::CSIZE
+        | ControlFlags::PARENB
+        | ControlFlags::PARODD
+        | ControlFlags::CSTOPB
+        | ControlFlags::CRTSCTS);
+    tio.control_flags |= ControlFlags::CLOCAL | ControlFlags::CREAD;
+
+    tio.control_flags |= match config.data_bits {
+        5 => ControlFlags::CS5,
+        6 => ControlFlags::CS6,
+        7 => ControlFlags::CS7,
+        8 => ControlFlags::CS8,
+        bits => {
+            return Err(GpioError::Uart(format!("{bits} data bits")));
+        }
+    };
+
+    match config.parity {
+        Parity::None => {}
+        Parity::Even => tio.control_flags |= ControlFlags::PARENB,
+        Parity::Odd => {
+            tio.control_flags |= ControlFlags::PARENB | ControlFlags::PARODD
+        }
+    }
+
+    match config.stop_bits {
+        1 => {}
+        2 => tio.control_flags |= ControlFlags::CSTOPB,
+        bits => {
+            return Err(GpioError::Uart(format!("{bits} stop bits")));
+        }
+    }
+
+    tio.input_flags &= !(InputFlags::IXON | InputFlags::IXOFF);
+    tio.output_flags &= !OutputFlags::OPOST;
+    tio.local_flags &= !(LocalFlags::ICANON | LocalFlags::ECHO);
+    tio.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+    tio.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+
+    termios::tcsetattr(file, SetArg::TCSANOW, &tio).map_err(err)
+}
+
+struct Port {
+    file: File,
+    path: String,
+    running: Arc<AtomicBool>,
+}
+
+// one port at a time, everything it receives goes
+// to the websocket and to a buffer for UartExpect
+#[derive(Clone)]
+pub struct Uart {
+    port: Arc<Mutex<Option<Port>>>,
+    rx_buf: Arc<Mutex<Vec<u8>>>,
+    rx_tx: broadcast::Sender<Vec<u8>>,
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        let (rx_tx, _) = broadcast::channel(100);
+        Uart {
+            port: Arc::new(Mutex::new(None)),
+            rx_buf: Arc::new(Mutex::new(Vec::new())),
+            rx_tx,
+        }
+    }
+
+    pub fn open(&self, config: &UartConfig) -> Result<(), GpioError> {
+        self.close();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&config.path)
+            .map_err(|e| {
+                let path = escape_html(&config.path);
+                GpioError::Uart(format!("{path}: {e}"))
+            })?;
+        configure_port(&file, config)?;
+
+        let reader = file
+            .try_clone()
+            .map_err(|e| GpioError::Uart(e.to_string()))?;
+        let running = Arc::new(AtomicBool::new(true));
+
+        self.rx_buf.lock().unwrap().clear();
+        self.spawn_reader(reader, running.clone());
+
+        *self.port.lock().unwrap() = Some(Port {
+            file,
+            path: config.path.clone(),
+            running,
+        });
+
+        Ok(())
+    }
+
+    fn spawn_reader(&self, mut reader: File, running: Arc<AtomicBool>) {
+        let rx_buf = self.rx_buf.clone();
+        let rx_tx = self.rx_tx.clone();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+
+            while running.load(Ordering::Relaxed) {
+                match reader.read(&mut buf) {
+                    Ok(0) => continue,
+                    Ok(n) => {
+                        let mut rx = rx_buf.lock().unwrap();
+                        rx.extend_from_slice(&buf[..n]);
+                        if rx.len() > RX_BUFFER_MAX {
+                            let extra = rx.len() - RX_BUFFER_MAX;
+                            rx.drain(..extra);
+                        }
+                        drop(rx);
+
+                        let _ = rx_tx.send(buf[..n].to_vec());
+                    }
+                    Err(e) => {
+                        println!("uart read failed: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    // the reader notices within one read timeout
+    pub fn close(&self) -> Option<String> {
+        let port = self.port.lock().unwrap().take()?;
+        port.running.store(false, Ordering::Relaxed);
+        Some(port.path)
+    }
+
+    pub fn send(&self, bytes: &[u8]) -> Result<(), GpioError> {
+        let mut port = self.port.lock().unwrap();
+        let port = port
+            .as_mut()
+            .ok_or_else(|| GpioError::Uart("port isn't open".to_string()))?;
+
+        port.file.write_all(bytes).map_err(|e| {
+            GpioError::Uart(format!("{}: {e}", escape_html(&port.path)))
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Vec<u8>> {
+        self.rx_tx.subscribe()
+    }
+
+    // waits until pattern shows up in what's been received,
+    // everything up to the end of the match is consumed
+    pub async fn expect(
+        &self,
+        pattern: &str,
+        limit: Duration,
+    ) -> Result<String, GpioError> {
+        if self.port.lock().unwrap().is_none() {
+            return Err(GpioError::Uart("port isn't open".to_string()));
+        }
+
+        // subscribe before looking so nothing
+        // slips in between the check and the wait
+        let mut rx = self.subscribe();
+        let deadline = Instant::now() + limit;
+
+        loop {
+            if let Some(matched) = self.take_match(pattern.as_bytes()) {
+                return Ok(String::from_utf8_lossy(&matched).into_owned());
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            match timeout(left, rx.recv()).await {
+                Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => {
+                }
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => {
+                    return Err(GpioError::Uart(format!(
+                        "didn't see `{}` within {}ms",
+                        escape_html(pattern),
+                        limit.as_millis()
+                    )));
+                }
+            }
+        }
+    }
+
+    fn take_match(&self, pattern: &[u8]) -> Option<Vec<u8>> {
+        let mut rx = self.rx_buf.lock().unwrap();
+        let end = if pattern.is_empty() {
+            0
+        } else {
+            rx.windows(pattern.len())
+                .position(|window| window == pattern)?
+                + pattern.len()
+        };
+
+        Some(rx.drain(..end).collect())
+    }
+}
+
+// so text typed into a form can carry control characters
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+pub async fn open_uart(
+    State(appstate): State<AppState>,
+    Form(input): Form<UartConfig>,
+) -> Html<String> {
+    match appstate.uart.open(&input) {
+        Ok(_) => log_info(
+            &appstate,
+            format!(
+                "UART {} open at {} {}{}{}",
+                escape_html(&input.path),
+                input.baud,
+                input.data_bits,
+                match input.parity {
+                    Parity::None => "N",
+                    Parity::Even => "E",
+                    Parity::Odd => "O",
+                },
+                input.stop_bits
+            ),
+        ),
+        Err(e) => log_error(&appstate, format!("failed to open uart: {e}")),
+    }
+}
+
+pub async fn close_uart(State(appstate): State<AppState>) -> Html<String> {
+    match appstate.uart.close() {
+        Some(path) => {
+            log_info(&appstate, format!("UART {} closed", escape_html(&path)))
+        }
+        None => log_error(&appstate, "UART isn't open"),
+    }
+}
+
+pub async fn handle_uart_websocket(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+) -> Response {
+    ws.on_upgrade(move |socket| handle_uart_socket(socket, state))
+}
+
+// its own socket so console traffic
+// doesn't get mixed up with the log
+async fn handle_uart_socket(socket: WebSocket, state: AppState) {
+    let mut uart_rx = state.uart.subscribe();
+    let (mut sender, mut receiver) = socket.split();
+
+    let mut send_task = tokio::spawn(async move {
+        loop {
+            match uart_rx.recv().await {
+                Ok(bytes) => {
+                    let text = String::from_utf8_lossy(&bytes);
+                    let fmsg = format!(
+                        r#"<pre id="uart-output" hx-swap-oob="beforeend">{}</pre>"#,
+                        escape_html(&text)
+                    );
+                    if sender.send(Message::text(fmsg)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let recv_state = state.clone();
+    let mut recv_task = tokio::spawn(async move {
+        let state = recv_state;
+        while let Some(Ok(msg)) = receiver.next().await {
+            let Message::Text(t) = msg else {
+                if let Message::Close(_) = msg {
+                    break;
+                }
+                continue;
+            };
+
+            let Ok(json) = serde_json::from_str::<serde_json::Value>(&t) else {
+                continue;
+            };
+            let Some(data) = json.get("uart_data").and_then(|d| d.as_str())
+            else {
+                continue;
+            };
+
+            let ending = match json.get("line_ending").and_then(|e| e.as_str())
+            {
+                Some("lf") => "\n",
+                Some("crlf") => "\r\n",
+                _ => "",
+            };
+
+            if state.read_only {
+                let _ = log_error(&state, "read only, not sending to uart");
+                continue;
+            }
+
+            let bytes = format!("{}{ending}", unescape(data)).into_bytes();
+            if let Err(e) = state.uart.send(&bytes) {
+                let _ = log_error(&state, e);
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = until_shutdown(&state) => {
+            send_task.abort();
+            recv_task.abort();
+        }
+        _ = (&mut send_task) => recv_task.abort(),
+        _ = (&mut recv_task) => send_task.abort(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::{openpty, OpenptyResult};
+    use std::{fs, os::fd::AsRawFd};
+
+    // the far end of a pty stands in for the device on the wire
+    fn open_pty(uart: &Uart) -> (File, OpenptyResult) {
+        let pty = openpty(None, None).unwrap();
+        let slave = format!("/proc/self/fd/{}", pty.slave.as_raw_fd());
+        let path = fs::read_link(slave).unwrap();
+
+        let config = UartConfig {
+            path: path.to_string_lossy().into_owned(),
+            baud: default_baud(),
+            data_bits: default_data_bits(),
+            parity: default_parity(),
+            stop_bits: default_stop_bits(),
+        };
+        uart.open(&config).unwrap();
+
+        let device = File::from(pty.master.try_clone().unwrap());
+        (device, pty)
+    }
+
+    #[tokio::test]
+    async fn expect_matches_what_the_device_sends() {
+        let uart = Uart::new();
+        let (mut device, _pty) = open_pty(&uart);
+
+        device.write_all(b"boot\r\nlogin: ").unwrap();
+        let seen = uart.expect("login:", Duration::from_secs(2)).await;
+        assert_eq!(seen.unwrap(), "boot\r\nlogin:");
+
+        uart.close();
+    }
+
+    #[tokio::test]
+    async fn expect_leaves_what_comes_after_the_match() {
+        let uart = Uart::new();
+        let (mut device, _pty) = open_pty(&uart);
+
+        device.write_all(b"OK\r\nREADY").unwrap();
+        let first = uart.expect("OK", Duration::from_secs(2)).await;
+        assert_eq!(first.unwrap(), "OK");
+        let second = uart.expect("READY", Duration::from_secs(2)).await;
+        assert_eq!(second.unwrap(), "\r\nREADY");
+
+        uart.close();
+    }
+
+    #[tokio::test]
+    async fn send_reaches_the_device() {
+        let uart = Uart::new();
+        let (mut device, _pty) = open_pty(&uart);
+
+        uart.send(b"AT\r\n").unwrap();
+        let mut buf = [0u8; 4];
+        device.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"AT\r\n");
+
+        uart.close();
+    }
+
+    #[tokio::test]
+    async fn timeout_escapes_the_pattern() {
+        let uart = Uart::new();
+        let (mut device, _pty) = open_pty(&uart);
+
+        device.write_all(b"nothing useful").unwrap();
+        let err = uart
+            .expect("<b>ok</b>", Duration::from_millis(50))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("&lt;b&gt;ok&lt;/b&gt;"), "{err}");
+        assert!(!err.contains('<'), "{err}");
+
+        uart.close();
+    }
+
+    #[tokio::test]
+    async fn expect_needs_an_open_port() {
+        let uart = Uart::new();
+
+        assert!(uart.expect("OK", Duration::from_millis(10)).await.is_err());
+        assert!(uart.send(b"AT").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{
+    config::{base_conf_path, conf_path, reload},
+    logger::{log_error, log_info, trigger_html},
+    profiles::{apply_config, validate_config},
+    AppState,
+};
+
+use nix::{
+    errno::Errno,
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify},
+};
+use std::{path::Path, thread};
+use tokio::runtime::Handle;
+
+fn changed(appstate: &AppState) {
+    let path = conf_path();
+    let refuse = |e: String| {
+        let _ = log_error(
+            appstate,
+            format!(
+                "Ignored the edit to {}: {e}, still running the last \
+                 good config",
+                path.display()
+            ),
+        );
+    };
+
+    match reload() {
+        Ok(None) => {}
+        Ok(Some(config)) => {
+            if let Err(e) = validate_config(appstate, &config) {
+                return refuse(e);
+            }
+
+            apply_config(appstate, config);
+            let _ = log_info(
+                appstate,
+                format!("Reloaded {} after it changed on disk", path.display()),
+            );
+            // pull the pins and the sequence again
+            // like a profile switch does
+            let _ = appstate
+                .log_tx
+                .send(trigger_html(&["pins-changed", "actions-changed"]));
+        }
+        Err(e) => refuse(e.to_string()),
+    }
+}
+
+// the directory is watched rather than the file, saves
+// (ours and most editors') rename a new file over the old one.
+// the name is checked on every event so profile switches follow
+pub fn watch_config(appstate: AppState) {
+    let dir = match base_conf_path().parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let inotify = match Inotify::init(InitFlags::IN_CLOEXEC) {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            println!("not watching the config: {e}");
+            return;
+        }
+    };
+
+    let flags = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO;
+    if let Err(e) = inotify.add_watch(dir, flags) {
+        println!("not watching {}: {e}", dir.display());
+        return;
+    }
+
+    // apply_config starts sensor polls, which need the runtime
+    let handle = Handle::current();
+    thread::spawn(move || {
+        let _runtime = handle.enter();
+        loop {
+            let events = match inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    println!("stopped watching the config: {e}");
+                    return;
+                }
+            };
+
+            let path = conf_path();
+            let name = path.file_name();
+            if events.iter().any(|event| event.name.as_deref() == name) {
+                changed(&appstate);
+            }
+        }
+    });
+}